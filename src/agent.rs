@@ -0,0 +1,295 @@
+//! Local signing agent, similar to ssh-agent.
+//!
+//! The agent holds a decrypted key in memory and answers newline-delimited JSON requests on a
+//! Unix socket, so the secret key never has to be exported into the shell environment.
+
+use crate::error::Error;
+use nostr_sdk::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::time::{Duration, timeout};
+
+/// Environment variable holding the agent socket path, exported by `login`.
+pub const AGENT_SOCKET_ENV: &str = "KANI_AGENT_SOCK";
+
+/// How long the agent waits for a client to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "method", content = "params", rename_all = "snake_case")]
+pub enum AgentRequest {
+    GetPublicKey,
+    SignEvent(UnsignedEvent),
+    Nip04Encrypt {
+        public_key: PublicKey,
+        content: String,
+    },
+    Nip04Decrypt {
+        public_key: PublicKey,
+        content: String,
+    },
+    Nip44Encrypt {
+        public_key: PublicKey,
+        content: String,
+    },
+    Nip44Decrypt {
+        public_key: PublicKey,
+        content: String,
+    },
+    Status,
+    Shutdown,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AgentResponse {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AgentStatus {
+    pub public_key: PublicKey,
+    pub idle_timeout: u64,
+}
+
+/// Create a fresh private directory for a new agent socket.
+pub fn new_socket_path() -> Result<PathBuf, Error> {
+    let base = dirs::runtime_dir().unwrap_or_else(std::env::temp_dir);
+    // `tempdir_in` creates the directory with mode 0700
+    let dir = tempfile::Builder::new()
+        .prefix("kani-agent-")
+        .tempdir_in(base)?
+        .keep();
+    Ok(dir.join("agent.sock"))
+}
+
+/// Serve signing requests until `shutdown` is requested or no request arrives for `idle_timeout`.
+pub async fn serve(keys: Keys, socket_path: &Path, idle_timeout: Duration) -> Result<(), Error> {
+    if socket_path.exists() {
+        fs::remove_file(socket_path)?;
+    }
+    let listener = UnixListener::bind(socket_path)?;
+    fs::set_permissions(socket_path, fs::Permissions::from_mode(0o600))?;
+
+    loop {
+        let Ok(accepted) = timeout(idle_timeout, listener.accept()).await else {
+            break;
+        };
+        let (stream, _) = accepted?;
+        match timeout(
+            REQUEST_TIMEOUT,
+            handle_connection(&keys, idle_timeout, stream),
+        )
+        .await
+        {
+            Ok(Ok(true)) => break,
+            Ok(Ok(false)) => {}
+            Ok(Err(e)) => eprintln!("Agent request failed: {e}"),
+            Err(_) => eprintln!("Agent request timed out"),
+        }
+    }
+
+    drop(listener);
+    let _ = fs::remove_file(socket_path);
+    if let Some(dir) = socket_path.parent() {
+        let _ = fs::remove_dir(dir);
+    }
+    Ok(())
+}
+
+/// Handle one request. Returns `true` if the agent should shut down.
+async fn handle_connection(
+    keys: &Keys,
+    idle_timeout: Duration,
+    stream: UnixStream,
+) -> Result<bool, Error> {
+    let (reader, mut writer) = stream.into_split();
+    let mut line = String::new();
+    BufReader::new(reader).read_line(&mut line).await?;
+
+    let mut shutdown = false;
+    let result = match serde_json::from_str::<AgentRequest>(&line) {
+        Ok(AgentRequest::Shutdown) => {
+            shutdown = true;
+            Ok(serde_json::Value::Null)
+        }
+        Ok(AgentRequest::Status) => serde_json::to_value(AgentStatus {
+            public_key: keys.public_key(),
+            idle_timeout: idle_timeout.as_secs(),
+        })
+        .map_err(|e| e.to_string()),
+        Ok(request) => answer(keys, request).await,
+        Err(e) => Err(format!("Invalid request: {e}")),
+    };
+
+    let response = match result {
+        Ok(result) => AgentResponse {
+            result: Some(result),
+            error: None,
+        },
+        Err(error) => AgentResponse {
+            result: None,
+            error: Some(error),
+        },
+    };
+    let mut out = serde_json::to_string(&response)?;
+    out.push('\n');
+    writer.write_all(out.as_bytes()).await?;
+    Ok(shutdown)
+}
+
+async fn answer(keys: &Keys, request: AgentRequest) -> Result<serde_json::Value, String> {
+    let value = match request {
+        AgentRequest::GetPublicKey => serde_json::to_value(keys.public_key()),
+        AgentRequest::SignEvent(unsigned) => {
+            serde_json::to_value(keys.sign_event(unsigned).await.map_err(|e| e.to_string())?)
+        }
+        AgentRequest::Nip04Encrypt {
+            public_key,
+            content,
+        } => serde_json::to_value(
+            keys.nip04_encrypt(&public_key, &content)
+                .await
+                .map_err(|e| e.to_string())?,
+        ),
+        AgentRequest::Nip04Decrypt {
+            public_key,
+            content,
+        } => serde_json::to_value(
+            keys.nip04_decrypt(&public_key, &content)
+                .await
+                .map_err(|e| e.to_string())?,
+        ),
+        AgentRequest::Nip44Encrypt {
+            public_key,
+            content,
+        } => serde_json::to_value(
+            keys.nip44_encrypt(&public_key, &content)
+                .await
+                .map_err(|e| e.to_string())?,
+        ),
+        AgentRequest::Nip44Decrypt {
+            public_key,
+            content,
+        } => serde_json::to_value(
+            keys.nip44_decrypt(&public_key, &content)
+                .await
+                .map_err(|e| e.to_string())?,
+        ),
+        AgentRequest::Status | AgentRequest::Shutdown => unreachable!("handled by the caller"),
+    };
+    value.map_err(|e| e.to_string())
+}
+
+/// Send a single request to the agent listening on `socket_path`.
+pub async fn request(
+    socket_path: &Path,
+    request: &AgentRequest,
+) -> Result<serde_json::Value, Error> {
+    let stream = UnixStream::connect(socket_path).await.map_err(|e| {
+        Error::Message(format!(
+            "Could not connect to the signing agent at {}: {e}. Run `eval $(kani-nostr-cli login)` again.",
+            socket_path.display()
+        ))
+    })?;
+    let (reader, mut writer) = stream.into_split();
+
+    let mut out = serde_json::to_string(request)?;
+    out.push('\n');
+    writer.write_all(out.as_bytes()).await?;
+
+    let mut line = String::new();
+    BufReader::new(reader).read_line(&mut line).await?;
+    let response: AgentResponse = serde_json::from_str(&line)?;
+    match (response.result, response.error) {
+        (_, Some(error)) => Err(Error::Message(format!("Signing agent error: {error}"))),
+        (Some(result), None) => Ok(result),
+        (None, None) => Ok(serde_json::Value::Null),
+    }
+}
+
+/// Signer that forwards every operation to a running agent.
+#[derive(Debug, Clone)]
+pub struct AgentSigner {
+    socket_path: PathBuf,
+}
+
+impl AgentSigner {
+    pub fn new(socket_path: PathBuf) -> Self {
+        Self { socket_path }
+    }
+
+    async fn call<T>(&self, req: AgentRequest) -> Result<T, SignerError>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        let value = request(&self.socket_path, &req)
+            .await
+            .map_err(SignerError::backend)?;
+        serde_json::from_value(value).map_err(SignerError::backend)
+    }
+}
+
+impl NostrSigner for AgentSigner {
+    fn backend(&self) -> SignerBackend<'_> {
+        SignerBackend::Custom("kani-agent".into())
+    }
+
+    fn get_public_key(&self) -> BoxedFuture<'_, Result<PublicKey, SignerError>> {
+        Box::pin(self.call(AgentRequest::GetPublicKey))
+    }
+
+    fn sign_event(&self, unsigned: UnsignedEvent) -> BoxedFuture<'_, Result<Event, SignerError>> {
+        Box::pin(self.call(AgentRequest::SignEvent(unsigned)))
+    }
+
+    fn nip04_encrypt<'a>(
+        &'a self,
+        public_key: &'a PublicKey,
+        content: &'a str,
+    ) -> BoxedFuture<'a, Result<String, SignerError>> {
+        Box::pin(self.call(AgentRequest::Nip04Encrypt {
+            public_key: *public_key,
+            content: content.to_string(),
+        }))
+    }
+
+    fn nip04_decrypt<'a>(
+        &'a self,
+        public_key: &'a PublicKey,
+        encrypted_content: &'a str,
+    ) -> BoxedFuture<'a, Result<String, SignerError>> {
+        Box::pin(self.call(AgentRequest::Nip04Decrypt {
+            public_key: *public_key,
+            content: encrypted_content.to_string(),
+        }))
+    }
+
+    fn nip44_encrypt<'a>(
+        &'a self,
+        public_key: &'a PublicKey,
+        content: &'a str,
+    ) -> BoxedFuture<'a, Result<String, SignerError>> {
+        Box::pin(self.call(AgentRequest::Nip44Encrypt {
+            public_key: *public_key,
+            content: content.to_string(),
+        }))
+    }
+
+    fn nip44_decrypt<'a>(
+        &'a self,
+        public_key: &'a PublicKey,
+        payload: &'a str,
+    ) -> BoxedFuture<'a, Result<String, SignerError>> {
+        Box::pin(self.call(AgentRequest::Nip44Decrypt {
+            public_key: *public_key,
+            content: payload.to_string(),
+        }))
+    }
+}
@@ -0,0 +1,360 @@
+//! Minimal BOLT-11 invoices: decoding enough to show what is being paid before paying it, and
+//! encoding the invoices of the test wallet service.
+//!
+//! Only the fields the CLI displays or checks are read; unknown tagged fields are skipped.
+
+use bech32::primitives::decode::{CheckedHrpstring, CheckedHrpstringError};
+use bech32::primitives::iter::Fe32IterExt;
+use bech32::{Bech32, Fe32, Hrp};
+use nostr::hashes::{Hash, sha256};
+use secp256k1::ecdsa::{RecoverableSignature, RecoveryId};
+use secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
+
+/// Expiry in seconds when the invoice has no `x` field.
+pub const DEFAULT_EXPIRY_SECS: u64 = 3600;
+
+/// Number of 5-bit words of the trailing recoverable signature.
+const SIGNATURE_WORDS: usize = 104;
+
+/// Number of 5-bit words of the leading timestamp.
+const TIMESTAMP_WORDS: usize = 7;
+
+/// Most 5-bit words of an `x` (expiry) field that still fit into a `u64`.
+const MAX_EXPIRY_WORDS: usize = 12;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Bolt11Error {
+    #[error("{0}")]
+    Bech32(#[from] CheckedHrpstringError),
+
+    #[error("{0}")]
+    Invalid(&'static str),
+
+    #[error("bad signature: {0}")]
+    Signature(#[from] secp256k1::Error),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invoice {
+    /// Amount in millisatoshis, `None` for invoices that let the payer choose
+    pub amount_msats: Option<u64>,
+    /// Creation time in seconds since the unix epoch
+    pub timestamp: u64,
+    /// Seconds after `timestamp` the invoice stays payable
+    pub expiry: u64,
+    pub description: Option<String>,
+    /// Hex SHA-256 of the description, used instead of `description` e.g. by zaps
+    pub description_hash: Option<String>,
+    /// Hex payment hash
+    pub payment_hash: String,
+    /// Node public key of the payee
+    pub payee: PublicKey,
+}
+
+impl Invoice {
+    /// Unix time after which the invoice can no longer be paid.
+    pub fn expires_at(&self) -> u64 {
+        self.timestamp.saturating_add(self.expiry)
+    }
+}
+
+/// Decode a BOLT-11 invoice, with or without a `lightning:` prefix.
+pub fn decode(invoice: &str) -> Result<Invoice, Bolt11Error> {
+    let invoice = invoice.trim();
+    let invoice = invoice
+        .strip_prefix("lightning:")
+        .or_else(|| invoice.strip_prefix("LIGHTNING:"))
+        .unwrap_or(invoice);
+
+    let parsed = CheckedHrpstring::new::<Bech32>(invoice)?;
+    let hrp = parsed.hrp().to_lowercase();
+    let amount_msats = parse_amount(&hrp)?;
+    let words: Vec<u8> = parsed
+        .fe32_iter::<std::vec::IntoIter<u8>>()
+        .map(Fe32::to_u8)
+        .collect();
+    if words.len() < TIMESTAMP_WORDS + SIGNATURE_WORDS {
+        return Err(Bolt11Error::Invalid("too short"));
+    }
+    let (data, signature) = words.split_at(words.len() - SIGNATURE_WORDS);
+
+    let timestamp = to_int(&data[..TIMESTAMP_WORDS]);
+    let mut expiry = DEFAULT_EXPIRY_SECS;
+    let mut description = None;
+    let mut description_hash = None;
+    let mut payment_hash = None;
+    let mut payee = None;
+
+    let mut fields = &data[TIMESTAMP_WORDS..];
+    while !fields.is_empty() {
+        if fields.len() < 3 {
+            return Err(Bolt11Error::Invalid("truncated tagged field"));
+        }
+        let len = (fields[1] as usize) << 5 | fields[2] as usize;
+        let value = fields
+            .get(3..3 + len)
+            .ok_or(Bolt11Error::Invalid("truncated tagged field"))?;
+        match fields[0] {
+            // p
+            1 if len == 52 => payment_hash = Some(hex(&to_bytes(value, false))),
+            // d
+            13 => {
+                description = Some(
+                    String::from_utf8(to_bytes(value, false))
+                        .map_err(|_| Bolt11Error::Invalid("description is not UTF-8"))?,
+                )
+            }
+            // h
+            23 if len == 52 => description_hash = Some(hex(&to_bytes(value, false))),
+            // x
+            6 if len > MAX_EXPIRY_WORDS => return Err(Bolt11Error::Invalid("expiry too long")),
+            6 => expiry = to_int(value),
+            // n
+            19 if len == 53 => payee = Some(PublicKey::from_slice(&to_bytes(value, false))?),
+            _ => {}
+        }
+        fields = &fields[3 + len..];
+    }
+
+    let signature = to_bytes(signature, false);
+    let signature = RecoverableSignature::from_compact(
+        &signature[..64],
+        RecoveryId::from_i32(signature[64] as i32)?,
+    )?;
+    // The signed data is padded with zero bits to a whole byte
+    let mut preimage = hrp.into_bytes();
+    preimage.extend(to_bytes(data, true));
+    let message = Message::from_digest(sha256::Hash::hash(&preimage).to_byte_array());
+    let secp = Secp256k1::verification_only();
+    let payee = match payee {
+        Some(payee) => {
+            secp.verify_ecdsa(&message, &signature.to_standard(), &payee)?;
+            payee
+        }
+        None => secp.recover_ecdsa(&message, &signature)?,
+    };
+
+    Ok(Invoice {
+        amount_msats,
+        timestamp,
+        expiry,
+        description,
+        description_hash,
+        payment_hash: payment_hash.ok_or(Bolt11Error::Invalid("missing payment hash"))?,
+        payee,
+    })
+}
+
+/// Encode and sign an invoice for `currency` (e.g. `bc`, `bcrt`). The fields of `invoice` are
+/// written as given; `payee` is left out and recovered from the signature by readers. Fails
+/// when the amount cannot be written in picobitcoin without overflowing.
+pub fn encode(
+    currency: &str,
+    invoice: &Invoice,
+    secret_key: &SecretKey,
+) -> Result<String, Bolt11Error> {
+    let mut hrp = format!("ln{currency}");
+    if let Some(msats) = invoice.amount_msats {
+        hrp.push_str(&format_amount(msats).ok_or(Bolt11Error::Invalid("amount out of range"))?);
+    }
+
+    let mut data = from_int(invoice.timestamp, TIMESTAMP_WORDS);
+    push_field(&mut data, 1, from_bytes(&from_hex(&invoice.payment_hash)));
+    if let Some(description) = &invoice.description {
+        push_field(&mut data, 13, from_bytes(description.as_bytes()));
+    }
+    if let Some(hash) = &invoice.description_hash {
+        push_field(&mut data, 23, from_bytes(&from_hex(hash)));
+    }
+    if invoice.expiry != DEFAULT_EXPIRY_SECS {
+        let words = (64 - invoice.expiry.leading_zeros() as usize)
+            .div_ceil(5)
+            .max(1);
+        push_field(&mut data, 6, from_int(invoice.expiry, words));
+    }
+
+    let mut preimage = hrp.clone().into_bytes();
+    preimage.extend(to_bytes(&data, true));
+    let message = Message::from_digest(sha256::Hash::hash(&preimage).to_byte_array());
+    let (recovery_id, signature) = Secp256k1::signing_only()
+        .sign_ecdsa_recoverable(&message, secret_key)
+        .serialize_compact();
+    let mut signature = signature.to_vec();
+    signature.push(recovery_id.to_i32() as u8);
+    data.extend(from_bytes(&signature));
+
+    let hrp = Hrp::parse(&hrp).expect("valid human readable part");
+    Ok(data
+        .into_iter()
+        .map(|w| Fe32::try_from(w).expect("5-bit word"))
+        .with_checksum::<Bech32>(&hrp)
+        .chars()
+        .collect())
+}
+
+/// Shortest amount suffix for `msats`, e.g. `2500u` for 250 000 000 msats. `None` when only a
+/// pico amount fits and it overflows a u64, which readers could not parse back.
+fn format_amount(msats: u64) -> Option<String> {
+    for (multiplier, msats_per_unit) in [('m', 100_000_000), ('u', 100_000), ('n', 100)] {
+        if msats.is_multiple_of(msats_per_unit) {
+            return Some(format!("{}{multiplier}", msats / msats_per_unit));
+        }
+    }
+    Some(format!("{}p", msats.checked_mul(10)?))
+}
+
+/// Read the amount from a human readable part like `lnbc2500u`.
+fn parse_amount(hrp: &str) -> Result<Option<u64>, Bolt11Error> {
+    let currency_and_amount = hrp
+        .strip_prefix("ln")
+        .ok_or(Bolt11Error::Invalid("not a lightning invoice"))?;
+    let Some(start) = currency_and_amount.find(|c: char| c.is_ascii_digit()) else {
+        return Ok(None);
+    };
+    let amount = &currency_and_amount[start..];
+    let (digits, multiplier) = match amount.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&amount[..i], Some(c)),
+        _ => (amount, None),
+    };
+    let value: u64 = digits
+        .parse()
+        .map_err(|_| Bolt11Error::Invalid("bad amount"))?;
+
+    // One bitcoin is 10^11 millisatoshis
+    let msats = match multiplier {
+        None => value.checked_mul(100_000_000_000),
+        Some('m') => value.checked_mul(100_000_000),
+        Some('u') => value.checked_mul(100_000),
+        Some('n') => value.checked_mul(100),
+        Some('p') if value.is_multiple_of(10) => Some(value / 10),
+        Some('p') => return Err(Bolt11Error::Invalid("sub-millisatoshi amount")),
+        Some(_) => return Err(Bolt11Error::Invalid("unknown amount multiplier")),
+    };
+    msats
+        .map(Some)
+        .ok_or(Bolt11Error::Invalid("amount out of range"))
+}
+
+fn to_int(words: &[u8]) -> u64 {
+    words.iter().fold(0, |acc, &w| acc << 5 | w as u64)
+}
+
+/// Pack 5-bit words into bytes. Leftover bits are dropped, or zero-padded into a last byte
+/// with `pad`.
+fn to_bytes(words: &[u8], pad: bool) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(words.len() * 5 / 8);
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for &w in words {
+        acc = acc << 5 | w as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    if pad && bits > 0 {
+        bytes.push((acc << (8 - bits)) as u8);
+    }
+    bytes
+}
+
+fn from_int(value: u64, words: usize) -> Vec<u8> {
+    (0..words)
+        .rev()
+        .map(|i| (value >> (5 * i) & 31) as u8)
+        .collect()
+}
+
+/// Split bytes into 5-bit words, zero-padding the last one.
+fn from_bytes(bytes: &[u8]) -> Vec<u8> {
+    let mut words = Vec::with_capacity((bytes.len() * 8).div_ceil(5));
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for &b in bytes {
+        acc = acc << 8 | b as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            words.push((acc >> bits & 31) as u8);
+        }
+        acc &= (1 << bits) - 1;
+    }
+    if bits > 0 {
+        words.push((acc << (5 - bits) & 31) as u8);
+    }
+    words
+}
+
+fn push_field(data: &mut Vec<u8>, tag: u8, value: Vec<u8>) {
+    data.extend([tag, (value.len() >> 5) as u8, (value.len() & 31) as u8]);
+    data.extend(value);
+}
+
+fn from_hex(hex: &str) -> Vec<u8> {
+    (0..hex.len() / 2)
+        .filter_map(|i| u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok())
+        .collect()
+}
+
+/// Lowercase hex encoding of `bytes`.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test vector from BOLT-11: "Please send $3 for a cup of coffee to the same peer, within one minute"
+    const COFFEE: &str = "lnbc2500u1pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdq5xysxxatsyp3k7enxv4jsxqzpuaztrnwngzn3kdzw5hydlzf03qdgm2hdq27cqv3agm2awhz5se903vruatfhq77w3ls4evs3ch9zw97j25emudupq63nyw24cg27h2rspfj9srp";
+
+    #[test]
+    fn decodes_bolt11_test_vector() {
+        let invoice = decode(COFFEE).unwrap();
+        assert_eq!(invoice.amount_msats, Some(250_000_000));
+        assert_eq!(invoice.timestamp, 1496314658);
+        assert_eq!(invoice.expiry, 60);
+        assert_eq!(invoice.description.as_deref(), Some("1 cup coffee"));
+        assert_eq!(
+            invoice.payment_hash,
+            "0001020304050607080900010203040506070809000102030405060708090102"
+        );
+        assert_eq!(
+            invoice.payee.to_string(),
+            "03e7156ae33b0a208d0744199163177e909e80176e55d97a2f221ede0f934dd9ad"
+        );
+        assert_eq!(parse_amount("lnbc").unwrap(), None);
+        assert_eq!(parse_amount("lntb20m").unwrap(), Some(2_000_000_000));
+        assert!(decode(&COFFEE.replace("fj9srp", "fj9srq")).is_err());
+    }
+
+    #[test]
+    fn encoded_invoices_decode_back() {
+        let secret_key = SecretKey::from_slice(&[7; 32]).unwrap();
+        let invoice = Invoice {
+            amount_msats: Some(21_000),
+            timestamp: 1_700_000_000,
+            expiry: 600,
+            description: Some("coffee".to_string()),
+            description_hash: None,
+            payment_hash: "ab".repeat(32),
+            payee: PublicKey::from_secret_key(&Secp256k1::new(), &secret_key),
+        };
+        let encoded = encode("bcrt", &invoice, &secret_key).unwrap();
+        assert!(encoded.starts_with("lnbcrt210n1"));
+        assert_eq!(decode(&encoded).unwrap(), invoice);
+        assert_eq!(format_amount(250_000_000).unwrap(), "2500u");
+        assert_eq!(format_amount(1).unwrap(), "10p");
+        assert_eq!(format_amount(u64::MAX), None);
+
+        // 13 words no longer fit into a u64
+        let forever = Invoice {
+            expiry: u64::MAX,
+            ..invoice
+        };
+        assert_eq!(forever.expires_at(), u64::MAX);
+        assert!(decode(&encode("bcrt", &forever, &secret_key).unwrap()).is_err());
+    }
+}
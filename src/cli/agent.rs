@@ -0,0 +1,131 @@
+use crate::agent::{AGENT_SOCKET_ENV, AgentRequest, AgentStatus, new_socket_path, request, serve};
+use crate::cli::common::decrypt_configured_key;
+use crate::cli::login::DEFAULT_IDLE_TIMEOUT;
+use crate::error::Error;
+use clap::{Parser, Subcommand};
+use nostr::prelude::{FromBech32, ToBech32};
+use nostr::{Keys, SecretKey};
+use std::io::{Read, Write};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+
+#[derive(Parser, Clone)]
+pub struct AgentCommand {
+    #[command(subcommand)]
+    subcommand: AgentSubcommand,
+}
+
+#[derive(Subcommand, Clone)]
+enum AgentSubcommand {
+    /// Decrypt the secret key and start a signing agent in the background
+    Start {
+        /// Named identity from the config file to load
+        #[clap(long)]
+        identity: Option<String>,
+        /// Seconds without requests after which the agent exits
+        #[clap(long, default_value_t = DEFAULT_IDLE_TIMEOUT)]
+        timeout: u64,
+    },
+    /// Stop the running signing agent
+    Stop,
+    /// Show the public key held by the running agent
+    Status,
+    /// Run the agent in the foreground, reading the secret key from stdin
+    #[command(hide = true)]
+    Serve {
+        #[clap(long)]
+        socket: PathBuf,
+        #[clap(long)]
+        timeout: u64,
+    },
+}
+
+pub async fn handle_agent_command(command: AgentCommand) -> Result<(), Error> {
+    match command.subcommand {
+        AgentSubcommand::Start { identity, timeout } => {
+            let secret_key = decrypt_configured_key(identity.as_ref())?;
+            let socket_path = spawn_agent(&secret_key, timeout)?;
+            print_exports(&socket_path);
+        }
+        AgentSubcommand::Stop => {
+            let socket_path = agent_socket()?;
+            request(&socket_path, &AgentRequest::Shutdown).await?;
+            println!("unset {AGENT_SOCKET_ENV}");
+            eprintln!("Signing agent stopped.");
+        }
+        AgentSubcommand::Status => {
+            let socket_path = agent_socket()?;
+            let status: AgentStatus =
+                serde_json::from_value(request(&socket_path, &AgentRequest::Status).await?)?;
+            println!("Socket: {}", socket_path.display());
+            println!("Public key: {}", status.public_key.to_bech32().unwrap());
+            println!("Idle timeout: {}s", status.idle_timeout);
+        }
+        AgentSubcommand::Serve { socket, timeout } => {
+            let mut secret_key = String::new();
+            std::io::stdin().read_to_string(&mut secret_key)?;
+            let keys = Keys::new(SecretKey::from_bech32(secret_key.trim())?);
+            serve(keys, &socket, Duration::from_secs(timeout)).await?;
+        }
+    }
+    Ok(())
+}
+
+fn agent_socket() -> Result<PathBuf, Error> {
+    std::env::var(AGENT_SOCKET_ENV)
+        .map(PathBuf::from)
+        .map_err(|_| {
+            Error::Message(format!(
+                "{AGENT_SOCKET_ENV} is not set; no agent is running"
+            ))
+        })
+}
+
+/// Start `agent serve` as a detached background process and hand it the key over a pipe.
+pub fn spawn_agent(secret_key: &str, idle_timeout: u64) -> Result<PathBuf, Error> {
+    let socket_path = new_socket_path()?;
+
+    let mut child = std::process::Command::new(std::env::current_exe()?)
+        .arg("agent")
+        .arg("serve")
+        .arg("--socket")
+        .arg(&socket_path)
+        .arg("--timeout")
+        .arg(idle_timeout.to_string())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        // Own process group, so Ctrl-C in the shell does not reach the agent
+        .process_group(0)
+        .spawn()?;
+
+    // The key goes through stdin so it never shows up in argv or the environment
+    let mut stdin = child
+        .stdin
+        .take()
+        .ok_or(Error::Message("Could not open agent stdin".to_string()))?;
+    stdin.write_all(secret_key.as_bytes())?;
+    drop(stdin);
+
+    wait_for_socket(&socket_path)?;
+    Ok(socket_path)
+}
+
+fn wait_for_socket(socket_path: &Path) -> Result<(), Error> {
+    for _ in 0..50 {
+        if socket_path.exists() {
+            return Ok(());
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    Err(Error::Message("Signing agent did not start".to_string()))
+}
+
+/// Print shell commands for `eval`, with user-facing messages on stderr.
+pub fn print_exports(socket_path: &Path) {
+    println!("export {AGENT_SOCKET_ENV}={}", socket_path.display());
+    eprintln!("Signing agent started. Commands will sign through it.");
+    eprintln!("Run `eval $(kani-nostr-cli logout)` to stop it.");
+}
@@ -1,40 +1,206 @@
+#[cfg(unix)]
+use crate::agent::{AGENT_SOCKET_ENV, AgentSigner};
 use crate::cli::CommonOptions;
-use crate::config::Config;
+use crate::config::{Config, Identity, load_config};
 use crate::error::Error;
+use dialoguer::Password;
+use nostr::nips::nip46::NostrConnectURI;
+use nostr::nips::nip49::EncryptedSecretKey;
+use nostr::prelude::{FromBech32, ToBech32};
+use nostr::util::BoxedFuture;
+use nostr::nips::{nip04, nip44};
+use nostr::{NostrSigner, PublicKey, SecretKey, Url};
+use nostr_connect::client::{AuthUrlHandler, NostrConnect};
+use nostr_sdk::prelude::IntoNostrSigner;
 use nostr_sdk::{Client, Keys};
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
-pub fn get_secret_key(
+/// How long to wait for a remote signer to answer a request.
+pub const NIP46_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Resolve the identity selected with `--identity`, falling back to the config default.
+pub fn get_identity<'a>(
     common_opts: &CommonOptions,
-    config: &Config,
-) -> Result<String, Error> {
+    config: &'a Config,
+) -> Result<Option<(&'a str, &'a Identity)>, Error> {
+    find_identity(common_opts.identity.as_ref(), config)
+}
+
+/// Look up `name` in the config, or the default identity when `name` is `None`.
+pub fn find_identity<'a>(
+    name: Option<&String>,
+    config: &'a Config,
+) -> Result<Option<(&'a str, &'a Identity)>, Error> {
+    let Some(name) = name.or(config.default_identity.as_ref()) else {
+        return Ok(None);
+    };
+    config
+        .identities
+        .get_key_value(name)
+        .map(|(name, identity)| Some((name.as_str(), identity)))
+        .ok_or(Error::Message(format!(
+            "Identity '{name}' not found in config"
+        )))
+}
+
+/// Prompt for a password and decrypt a NIP-49 encrypted secret key.
+pub fn decrypt_secret_key(encrypted_key_bech32: &str, prompt: &str) -> Result<String, Error> {
+    let password = Password::new().with_prompt(prompt).interact()?;
+    let encrypted_key = EncryptedSecretKey::from_bech32(encrypted_key_bech32)?;
+    let secret_key = encrypted_key.decrypt(&password)?;
+    Ok(secret_key.to_bech32().unwrap())
+}
+
+/// Decrypt the NIP-49 key of `identity` (or the default one) from the config file.
+pub fn decrypt_configured_key(identity: Option<&String>) -> Result<String, Error> {
+    let config = load_config()?;
+
+    let encrypted_key_bech32 = match find_identity(identity, &config)? {
+        Some((name, identity)) => identity.encrypted_secret_key.clone().ok_or(Error::Message(
+            format!("Identity '{name}' has no secret key"),
+        ))?,
+        None => config.encrypted_secret_key.ok_or(Error::Message(
+            "No encrypted secret key found in config. Please run `key generate --wizard` or `key encrypt` first.".to_string(),
+        ))?,
+    };
+
+    decrypt_secret_key(
+        &encrypted_key_bech32,
+        "Enter password to decrypt secret key",
+    )
+}
+
+pub fn get_secret_key(common_opts: &CommonOptions, config: &Config) -> Result<String, Error> {
     if let Some(sk) = &common_opts.secret_key {
         return Ok(sk.clone());
     }
-    if let Ok(sk) = std::env::var("NOSTR_SECRET_KEY") {
+    // An explicitly selected identity wins over a key exported by `login`
+    if common_opts.identity.is_none()
+        && let Ok(sk) = std::env::var("NOSTR_SECRET_KEY")
+    {
         return Ok(sk);
     }
+    if let Some((name, identity)) = get_identity(common_opts, config)? {
+        let encrypted_key = identity
+            .encrypted_secret_key
+            .as_ref()
+            .ok_or(Error::Message(format!(
+                "Identity '{name}' has no secret key"
+            )))?;
+        return decrypt_secret_key(
+            encrypted_key,
+            &format!("Enter password to decrypt secret key for '{name}'"),
+        );
+    }
     if let Some(sk) = &config.secret_key {
         return Ok(sk.clone());
     }
+    // Nothing in the environment, e.g. when logged in through the signing agent which
+    // never hands out the key itself
+    if let Some(encrypted_key) = &config.encrypted_secret_key {
+        return decrypt_secret_key(encrypted_key, "Enter password to decrypt secret key");
+    }
     Err(Error::SecretKeyMissing)
 }
 
+/// Resolve the signer for commands that publish events.
+///
+/// A `--secret-key` or `--bunker` given on the command line wins. Otherwise a running signing
+/// agent (see `login`) is preferred over raw key material, unless an identity is given
+/// explicitly. Bunkers configured for the identity or globally are used before local keys.
+pub fn get_signer(
+    common_opts: &CommonOptions,
+    config: &Config,
+) -> Result<Arc<dyn NostrSigner>, Error> {
+    if common_opts.secret_key.is_none() {
+        if let Some(uri) = &common_opts.bunker {
+            return bunker_signer(uri, get_bunker_app_key(common_opts, config)?);
+        }
+        #[cfg(unix)]
+        if common_opts.identity.is_none()
+            && let Ok(socket_path) = std::env::var(AGENT_SOCKET_ENV)
+        {
+            return Ok(AgentSigner::new(PathBuf::from(socket_path)).into_nostr_signer());
+        }
+        if let Some((_, identity)) = get_identity(common_opts, config)?
+            && let Some(uri) = &identity.bunker_uri
+        {
+            return bunker_signer(uri, identity.bunker_app_key.as_ref());
+        }
+        if get_identity(common_opts, config)?.is_none()
+            && let Some(uri) = &config.bunker_uri
+        {
+            return bunker_signer(uri, config.bunker_app_key.as_ref());
+        }
+    }
+    let secret_key_str = get_secret_key(common_opts, config)?;
+    let keys = Keys::new(SecretKey::from_bech32(&secret_key_str)?);
+    Ok(keys.into_nostr_signer())
+}
+
+/// App key stored for the selected identity or globally, used with `--bunker`.
+fn get_bunker_app_key<'a>(
+    common_opts: &CommonOptions,
+    config: &'a Config,
+) -> Result<Option<&'a String>, Error> {
+    Ok(match get_identity(common_opts, config)? {
+        Some((_, identity)) => identity.bunker_app_key.as_ref(),
+        None => config.bunker_app_key.as_ref(),
+    })
+}
+
+/// Build a NIP-46 signer for `uri`, talking to it with `app_key` or a throwaway key.
+pub fn bunker_signer(uri: &str, app_key: Option<&String>) -> Result<Arc<dyn NostrSigner>, Error> {
+    let uri = NostrConnectURI::parse(uri)?;
+    let app_keys = match app_key {
+        Some(app_key) => Keys::parse(app_key)?,
+        None => Keys::generate(),
+    };
+    let mut signer = NostrConnect::new(uri, app_keys, NIP46_TIMEOUT, None)?;
+    signer.auth_url_handler(PrintAuthUrl);
+    Ok(signer.into_nostr_signer())
+}
+
+/// Shows `auth_url` challenges from the remote signer so the user can approve them.
+#[derive(Debug)]
+struct PrintAuthUrl;
+
+impl AuthUrlHandler for PrintAuthUrl {
+    fn on_auth_url(&self, auth_url: Url) -> BoxedFuture<'_, nostr::Result<()>> {
+        Box::pin(async move {
+            eprintln!("The remote signer requires authorization. Open this URL to continue:");
+            eprintln!("{auth_url}");
+            Ok(())
+        })
+    }
+}
+
 pub fn get_relays(common_opts: &CommonOptions, config: &Config) -> Vec<String> {
     if !common_opts.relay.is_empty() {
-        common_opts.relay.clone()
-    } else {
-        config.relays.clone().unwrap_or_default()
+        return common_opts.relay.clone();
     }
+    if let Ok(Some((_, identity))) = get_identity(common_opts, config)
+        && let Some(relays) = &identity.relays
+    {
+        return relays.clone();
+    }
+    config.relays.clone().unwrap_or_default()
 }
 
-pub async fn connect_client(keys: Keys, relays: Vec<String>) -> Result<Client, Error> {
+pub async fn connect_client<T>(signer: T, relays: Vec<String>) -> Result<Client, Error>
+where
+    T: IntoNostrSigner,
+{
     if relays.is_empty() {
         return Err(Error::Message(
             "No relays provided in args or config".to_string(),
         ));
     }
 
-    let client = Client::new(keys);
+    let client = Client::new(signer);
     for relay in relays {
         client.add_relay(relay).await?;
     }
@@ -42,3 +208,27 @@ pub async fn connect_client(keys: Keys, relays: Vec<String>) -> Result<Client, E
 
     Ok(client)
 }
+
+/// Whether `content` is a NIP-04 payload (`<base64>?iv=<base64>`) rather than a NIP-44 one.
+pub fn is_nip04(content: &str) -> bool {
+    content.contains("?iv=")
+}
+
+/// Decrypt a NIP-46 or NIP-47 payload, telling NIP-04 and NIP-44 apart.
+pub fn decrypt_message(
+    secret_key: &SecretKey,
+    sender: &PublicKey,
+    content: &str,
+) -> Result<String, Error> {
+    if is_nip04(content) {
+        Ok(nip04::decrypt(secret_key, sender, content)?)
+    } else {
+        Ok(nip44::decrypt(secret_key, sender, content)?)
+    }
+}
+
+/// Convert an amount given on the command line, rejecting ones that do not fit in msats.
+pub fn sats_to_msats(sats: u64) -> Result<u64, Error> {
+    sats.checked_mul(1000)
+        .ok_or_else(|| Error::Message(format!("Amount of {sats} sats is too large")))
+}
@@ -1,4 +1,11 @@
+use crate::config::{
+    Config, SECRET_KEYS, get_config_path, load_config, save_config, save_config_raw,
+};
 use clap::{Parser, Subcommand};
+use dialoguer::{Confirm, theme::ColorfulTheme};
+use serde::Deserialize;
+use std::io::Write;
+use toml::Value;
 
 #[derive(Parser, Clone)]
 pub struct ConfigCommand {
@@ -10,6 +17,31 @@ pub struct ConfigCommand {
 enum ConfigSubCommand {
     /// Show the path to the config file
     Path,
+    /// Show the config file with secrets redacted
+    Show,
+    /// Get a value by key (e.g. `relays`, `identities.alice.relays`)
+    Get {
+        /// Dotted key
+        key: String,
+    },
+    /// Set a value by key
+    Set {
+        /// Dotted key
+        key: String,
+        /// Value, or several values for a list such as `relays`
+        #[clap(required = true)]
+        values: Vec<String>,
+        /// Append to a list instead of replacing it
+        #[clap(long, default_value_t = false)]
+        append: bool,
+    },
+    /// Remove a value by key
+    Unset {
+        /// Dotted key
+        key: String,
+    },
+    /// Open the config file in $EDITOR and validate it before saving
+    Edit,
 }
 
 use crate::error::Error;
@@ -17,9 +49,311 @@ use crate::error::Error;
 pub async fn handle_config_command(config_command: ConfigCommand) -> Result<(), Error> {
     match config_command.command {
         ConfigSubCommand::Path => {
-            let path = crate::config::get_config_path()?;
+            let path = get_config_path()?;
             println!("{}", path.display());
         }
+        ConfigSubCommand::Show => {
+            let mut value = Value::try_from(load_config()?)?;
+            redact(&mut value);
+            print!("{}", toml::to_string_pretty(&value)?);
+        }
+        ConfigSubCommand::Get { key } => {
+            let value = Value::try_from(load_config()?)?;
+            match lookup(&value, &key) {
+                Some(Value::String(s)) => println!("{s}"),
+                Some(Value::Array(items)) => {
+                    for item in items {
+                        match item {
+                            Value::String(s) => println!("{s}"),
+                            other => println!("{other}"),
+                        }
+                    }
+                }
+                Some(table @ Value::Table(_)) => print!("{}", toml::to_string_pretty(table)?),
+                Some(other) => println!("{other}"),
+                None => return Err(Error::Message(format!("Key '{key}' is not set"))),
+            }
+        }
+        ConfigSubCommand::Set {
+            key,
+            values,
+            append,
+        } => {
+            let mut value = Value::try_from(load_config()?)?;
+            let config = set(&mut value, &key, &values, append)?;
+            save_config(&config)?;
+            println!("Updated '{key}'.");
+        }
+        ConfigSubCommand::Unset { key } => {
+            let mut value = Value::try_from(load_config()?)?;
+            if remove(&mut value, &key).is_none() {
+                return Err(Error::Message(format!("Key '{key}' is not set")));
+            }
+            let config = Config::deserialize(value)?;
+            save_config(&config)?;
+            println!("Removed '{key}'.");
+        }
+        ConfigSubCommand::Edit => edit_config()?,
+    }
+    Ok(())
+}
+
+/// Set `key` in the config `value` to `values` and check that the result is a valid config.
+///
+/// Command line values are untyped, so they are tried as integers and booleans first, then as
+/// strings, and a single value also as a one-item list for keys such as `relays`.
+fn set(value: &mut Value, key: &str, values: &[String], append: bool) -> Result<Config, Error> {
+    let existing = if append {
+        match lookup(value, key) {
+            Some(Value::Array(items)) => Some(items.clone()),
+            Some(_) => return Err(Error::Message(format!("Key '{key}' is not a list"))),
+            None => Some(Vec::new()),
+        }
+    } else {
+        None
+    };
+
+    let mut first_error = None;
+    for candidate in candidate_values(values, existing) {
+        insert(value, key, candidate)?;
+        match Config::deserialize(value.clone()) {
+            Ok(config) => {
+                // Unknown keys are silently dropped by deserialization
+                if lookup(&Value::try_from(&config)?, key).is_none() {
+                    return Err(Error::Message(format!("Unknown config key '{key}'")));
+                }
+                return Ok(config);
+            }
+            Err(e) => {
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    Err(first_error
+        .map(Error::from)
+        .unwrap_or_else(|| Error::Message(format!("No value given for '{key}'"))))
+}
+
+/// The ways `values` may be meant, most specific first.
+fn candidate_values(values: &[String], existing: Option<Vec<Value>>) -> Vec<Value> {
+    let typed: Vec<Value> = values.iter().map(|v| parse_value(v)).collect();
+    let strings: Vec<Value> = values.iter().cloned().map(Value::String).collect();
+    match (existing, values) {
+        (Some(items), _) => vec![
+            Value::Array([items.clone(), typed].concat()),
+            Value::Array([items, strings].concat()),
+        ],
+        (None, [_]) => vec![
+            typed[0].clone(),
+            strings[0].clone(),
+            Value::Array(typed),
+            Value::Array(strings),
+        ],
+        (None, _) => vec![Value::Array(typed), Value::Array(strings)],
     }
+}
+
+/// Read a value given on the command line as an integer or boolean where it looks like one.
+fn parse_value(value: &str) -> Value {
+    if let Ok(integer) = value.parse::<i64>() {
+        Value::Integer(integer)
+    } else if let Ok(boolean) = value.parse::<bool>() {
+        Value::Boolean(boolean)
+    } else {
+        Value::String(value.to_string())
+    }
+}
+
+fn lookup<'a>(value: &'a Value, key: &str) -> Option<&'a Value> {
+    key.split('.').try_fold(value, |v, part| v.get(part))
+}
+
+fn insert(value: &mut Value, key: &str, new_value: Value) -> Result<(), Error> {
+    let (parents, last) = match key.rsplit_once('.') {
+        Some((parents, last)) => (Some(parents), last),
+        None => (None, key),
+    };
+    let mut table = value
+        .as_table_mut()
+        .ok_or(Error::Message("Config is not a table".to_string()))?;
+    for part in parents.into_iter().flat_map(|p| p.split('.')) {
+        table = table
+            .entry(part)
+            .or_insert_with(|| Value::Table(Default::default()))
+            .as_table_mut()
+            .ok_or(Error::Message(format!("Key '{part}' is not a table")))?;
+    }
+    table.insert(last.to_string(), new_value);
     Ok(())
 }
+
+fn remove(value: &mut Value, key: &str) -> Option<Value> {
+    let (parents, last) = match key.rsplit_once('.') {
+        Some((parents, last)) => (Some(parents), last),
+        None => (None, key),
+    };
+    let mut table = value.as_table_mut()?;
+    for part in parents.into_iter().flat_map(|p| p.split('.')) {
+        table = table.get_mut(part)?.as_table_mut()?;
+    }
+    table.remove(last)
+}
+
+fn redact(value: &mut Value) {
+    if let Value::Table(table) = value {
+        for (key, v) in table.iter_mut() {
+            if SECRET_KEYS.contains(&key.as_str()) {
+                *v = Value::String("<redacted>".to_string());
+            } else {
+                redact(v);
+            }
+        }
+    }
+}
+
+fn edit_config() -> Result<(), Error> {
+    let config_path = get_config_path()?;
+    let original = if config_path.exists() {
+        std::fs::read_to_string(&config_path)?
+    } else {
+        String::new()
+    };
+
+    let mut file = tempfile::Builder::new().suffix(".toml").tempfile()?;
+    file.write_all(original.as_bytes())?;
+    file.flush()?;
+
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+
+    loop {
+        // The editor may carry its own arguments, e.g. `code --wait`
+        let mut parts = editor.split_whitespace();
+        let program = parts
+            .next()
+            .ok_or(Error::Message("$EDITOR is empty".to_string()))?;
+        let status = std::process::Command::new(program)
+            .args(parts)
+            .arg(file.path())
+            .status()?;
+        if !status.success() {
+            return Err(Error::Message(format!("Editor exited with {status}")));
+        }
+
+        let edited = std::fs::read_to_string(file.path())?;
+        if edited == original {
+            println!("No changes made.");
+            return Ok(());
+        }
+        match toml::from_str::<Config>(&edited) {
+            Ok(_) => {
+                save_config_raw(&edited)?;
+                println!("Config saved.");
+                return Ok(());
+            }
+            Err(e) => {
+                eprintln!("Invalid config: {e}");
+                if !Confirm::with_theme(&ColorfulTheme::default())
+                    .with_prompt("Edit again?")
+                    .default(true)
+                    .interact()?
+                {
+                    println!("Changes discarded.");
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_value() -> Value {
+        toml::from_str(
+            r#"
+            relays = ["wss://relay.example.com"]
+
+            [wallets.w]
+            public_key = "abc"
+            relays = []
+            encrypted_secret = "ncryptsec1..."
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn looks_up_inserts_and_removes_dotted_keys() {
+        let mut value = config_value();
+        assert_eq!(
+            lookup(&value, "wallets.w.public_key"),
+            Some(&Value::String("abc".to_string()))
+        );
+        assert_eq!(lookup(&value, "wallets.x.public_key"), None);
+
+        insert(
+            &mut value,
+            "identities.bot.wallet",
+            Value::String("w".to_string()),
+        )
+        .unwrap();
+        assert_eq!(
+            lookup(&value, "identities.bot.wallet"),
+            Some(&Value::String("w".to_string()))
+        );
+        assert!(insert(&mut value, "relays.first", Value::Boolean(true)).is_err());
+
+        assert!(remove(&mut value, "identities.bot.wallet").is_some());
+        assert!(remove(&mut value, "identities.bot.wallet").is_none());
+        assert!(remove(&mut value, "wallets.x.public_key").is_none());
+    }
+
+    #[test]
+    fn coerces_values_to_the_type_of_the_key() {
+        let mut value = config_value();
+
+        let config = set(
+            &mut value,
+            "wallets.w.max_payment",
+            &strings(&["500"]),
+            false,
+        )
+        .unwrap();
+        assert_eq!(config.wallets["w"].max_payment, Some(500));
+
+        // A number where a string is expected stays a string
+        let config = set(&mut value, "default_wallet", &strings(&["1"]), false).unwrap();
+        assert_eq!(config.default_wallet.as_deref(), Some("1"));
+
+        let relay = strings(&["wss://other.example.com"]);
+        let config = set(&mut value, "relays", &relay, false).unwrap();
+        assert_eq!(config.relays, Some(relay.clone()));
+        let config = set(
+            &mut value,
+            "relays",
+            &strings(&["wss://third.example.com"]),
+            true,
+        )
+        .unwrap();
+        assert_eq!(config.relays.unwrap().len(), 2);
+
+        assert!(
+            set(
+                &mut config_value(),
+                "wallets.w.max_payment",
+                &strings(&["lots"]),
+                false
+            )
+            .is_err()
+        );
+        assert!(set(&mut config_value(), "no_such_key", &strings(&["1"]), false).is_err());
+        assert_eq!(parse_value("true"), Value::Boolean(true));
+    }
+}
@@ -1,10 +1,18 @@
 use crate::cli::CommonOptions;
-use crate::cli::common::{connect_client, get_relays, get_secret_key};
+use crate::cli::common::{connect_client, get_relays, get_signer};
 use crate::config::load_config;
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
+use colored::*;
+use comfy_table::{Cell, Table, presets::UTF8_FULL};
+use dialoguer::{Confirm, theme::ColorfulTheme};
+use indicatif::{ProgressBar, ProgressStyle};
+use nostr::Keys;
 use nostr::prelude::FromBech32;
-use nostr::{Keys, SecretKey};
 use nostr_sdk::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::Arc;
 use std::time::Duration;
 
 #[derive(Parser, Clone)]
@@ -17,18 +25,91 @@ pub struct ContactCommand {
 
 #[derive(Subcommand, Clone)]
 pub enum ContactSubcommand {
-    /// Add a contact to your list
+    /// Add contacts to your existing list
     Add {
-        /// Public keys to follow
+        /// Public keys to follow. Format: <npub|hex>[@wss://relay.example.com][#petname]
         pubkeys: Vec<String>,
+        /// Relay hint for contacts given without an inline relay
+        #[clap(long)]
+        relay: Option<String>,
+        /// Petname for the contact (only with a single public key)
+        #[clap(long)]
+        petname: Option<String>,
+        /// Publish even if the current contact list could not be fetched
+        #[clap(long, default_value_t = false)]
+        force: bool,
+    },
+    /// Remove contacts from your existing list
+    Remove {
+        /// Public keys to unfollow
+        pubkeys: Vec<String>,
+        /// Publish even if the current contact list could not be fetched
+        #[clap(long, default_value_t = false)]
+        force: bool,
     },
     /// List contacts
     List {
         /// Public key to get the contact list for
         pubkey: String,
+        /// Print one JSON object per contact instead of a table
+        #[clap(long, default_value_t = false)]
+        json: bool,
+    },
+    /// Export a contact list to a JSON or CSV file
+    Export {
+        /// Output file
+        file: String,
+        /// Public key whose list to export (defaults to your own)
+        #[clap(long)]
+        pubkey: Option<String>,
+        /// File format (inferred from the file extension if omitted)
+        #[clap(long, value_enum)]
+        format: Option<ContactFileFormat>,
+    },
+    /// Publish a contact list from a JSON or CSV file
+    Import {
+        /// Input file
+        file: String,
+        /// Replace the current list instead of merging into it
+        #[clap(long, default_value_t = false)]
+        replace: bool,
+        /// File format (inferred from the file extension if omitted)
+        #[clap(long, value_enum)]
+        format: Option<ContactFileFormat>,
+        /// Publish even if the current contact list could not be fetched
+        #[clap(long, default_value_t = false)]
+        force: bool,
+    },
+    /// Compare a file or another public key's contact list with yours
+    Diff {
+        /// Contact list file to compare against
+        #[clap(long, conflicts_with = "pubkey", required_unless_present = "pubkey")]
+        file: Option<String>,
+        /// Public key whose contact list to compare against
+        #[clap(long)]
+        pubkey: Option<String>,
+        /// File format (inferred from the file extension if omitted)
+        #[clap(long, value_enum)]
+        format: Option<ContactFileFormat>,
+    },
+    /// Show every version of a contact list still held by relays
+    History {
+        /// Public key whose history to show (defaults to your own)
+        pubkey: Option<String>,
+    },
+    /// Republish an older version of your contact list
+    Restore {
+        /// ID of the contact list event to restore (note or hex)
+        event_id: String,
     },
 }
 
+#[derive(ValueEnum, Clone, Copy, PartialEq, Debug)]
+pub enum ContactFileFormat {
+    Json,
+    Csv,
+}
+
 use crate::error::Error;
 
 pub async fn handle_contact_command(command: ContactCommand) -> Result<(), Error> {
@@ -36,78 +117,941 @@ pub async fn handle_contact_command(command: ContactCommand) -> Result<(), Error
     let relays = get_relays(&command.common, &config);
 
     match command.subcommand {
-        ContactSubcommand::Add { pubkeys } => {
-            let secret_key_str = get_secret_key(&command.common, &config)?;
-            set_contact_list(pubkeys, secret_key_str, relays).await?;
+        ContactSubcommand::Add {
+            pubkeys,
+            relay,
+            petname,
+            force,
+        } => {
+            if petname.is_some() && pubkeys.len() > 1 {
+                return Err(Error::Message(
+                    "--petname can only be used with a single public key".to_string(),
+                ));
+            }
+            let mut contacts = pubkeys
+                .iter()
+                .map(|s| parse_contact(s))
+                .collect::<Result<Vec<_>, _>>()?;
+            let relay_url = relay.map(|r| RelayUrl::parse(&r)).transpose()?;
+            for contact in contacts.iter_mut() {
+                if contact.relay_url.is_none() {
+                    contact.relay_url = relay_url.clone();
+                }
+                if contact.alias.is_none() {
+                    contact.alias = petname.clone();
+                }
+            }
+            let signer = get_signer(&command.common, &config)?;
+            add_contacts(contacts, force, signer, relays).await?;
+        }
+        ContactSubcommand::Remove { pubkeys, force } => {
+            let signer = get_signer(&command.common, &config)?;
+            remove_contacts(pubkeys, force, signer, relays).await?;
+        }
+        ContactSubcommand::List { pubkey, json } => {
+            get_contact_list(pubkey, json, relays).await?;
+        }
+        ContactSubcommand::Export {
+            file,
+            pubkey,
+            format,
+        } => {
+            let pubkey = match pubkey {
+                Some(pubkey) => parse_public_key(&pubkey)?,
+                None => {
+                    get_signer(&command.common, &config)?
+                        .get_public_key()
+                        .await?
+                }
+            };
+            export_contacts(pubkey, file, format, relays).await?;
+        }
+        ContactSubcommand::Import {
+            file,
+            replace,
+            format,
+            force,
+        } => {
+            let contacts = read_contact_file(&file, format)?;
+            let signer = get_signer(&command.common, &config)?;
+            if replace {
+                replace_contacts(contacts, force, signer, relays).await?;
+            } else {
+                add_contacts(contacts, force, signer, relays).await?;
+            }
+        }
+        ContactSubcommand::Diff {
+            file,
+            pubkey,
+            format,
+        } => {
+            let own_pubkey = get_signer(&command.common, &config)?
+                .get_public_key()
+                .await?;
+            diff_contacts(own_pubkey, file, pubkey, format, relays).await?;
+        }
+        ContactSubcommand::History { pubkey } => {
+            let pubkey = match pubkey {
+                Some(pubkey) => parse_public_key(&pubkey)?,
+                None => {
+                    get_signer(&command.common, &config)?
+                        .get_public_key()
+                        .await?
+                }
+            };
+            contact_history(pubkey, relays).await?;
         }
-        ContactSubcommand::List { pubkey } => {
-            get_contact_list(pubkey, relays).await?;
+        ContactSubcommand::Restore { event_id } => {
+            let signer = get_signer(&command.common, &config)?;
+            restore_contacts(event_id, signer, relays).await?;
         }
     }
     Ok(())
 }
 
-async fn set_contact_list(
-    pubkeys: Vec<String>,
-    secret_key_str: String,
+fn parse_public_key(pubkey: &str) -> Result<PublicKey, Error> {
+    if let Ok(pk) = PublicKey::from_bech32(pubkey) {
+        Ok(pk)
+    } else {
+        Ok(PublicKey::from_hex(pubkey)?)
+    }
+}
+
+/// Fetch the most recent kind-3 event published by `pubkey`.
+async fn fetch_contact_list_event(
+    client: &Client,
+    pubkey: PublicKey,
+    relays: &[String],
+) -> Result<Option<Event>, Error> {
+    let filter = Filter::new()
+        .author(pubkey)
+        .kind(Kind::ContactList)
+        .limit(1);
+    let timeout = Duration::from_secs(10);
+    let events = client
+        .fetch_events_from(relays.iter().map(|s| s.as_str()), filter, timeout)
+        .await?;
+    Ok(events.first_owned())
+}
+
+/// Fetch our current contact list, refusing to continue without one unless `force` is set.
+///
+/// Publishing a kind-3 event replaces the whole list, so building on top of nothing would
+/// silently wipe every follow we have.
+async fn fetch_own_contact_list(
+    client: &Client,
+    public_key: PublicKey,
+    relays: &[String],
+    force: bool,
+) -> Result<Option<Event>, Error> {
+    let result = fetch_contact_list_event(client, public_key, relays).await;
+    match result {
+        Ok(Some(event)) => Ok(Some(event)),
+        Ok(None) | Err(_) if force => {
+            eprintln!("Warning: current contact list could not be fetched, publishing anyway.");
+            Ok(None)
+        }
+        Ok(None) => Err(Error::Message(
+            "Could not fetch your current contact list from any relay. Use --force to publish anyway."
+                .to_string(),
+        )),
+        Err(e) => Err(Error::Message(format!(
+            "Could not fetch your current contact list: {e}. Use --force to publish anyway."
+        ))),
+    }
+}
+
+/// Parse a contact given as `<npub|hex>[@relay][#petname]`.
+fn parse_contact(input: &str) -> Result<Contact, Error> {
+    let (rest, alias) = match input.split_once('#') {
+        Some((rest, alias)) if !alias.is_empty() => (rest, Some(alias.to_string())),
+        Some((rest, _)) => (rest, None),
+        None => (input, None),
+    };
+    let (pubkey, relay_url) = match rest.split_once('@') {
+        Some((pubkey, relay)) => (pubkey, Some(RelayUrl::parse(relay)?)),
+        None => (rest, None),
+    };
+
+    let mut contact = Contact::new(parse_public_key(pubkey)?);
+    contact.relay_url = relay_url;
+    contact.alias = alias;
+    Ok(contact)
+}
+
+fn contact_tag(contact: Contact) -> Tag {
+    Tag::from_standardized_without_cell(TagStandard::PublicKey {
+        public_key: contact.public_key,
+        relay_url: contact.relay_url,
+        alias: contact.alias,
+        uppercase: false,
+    })
+}
+
+/// The p tag `tag` with the relay hint and petname `contact` sets, keeping the ones it leaves
+/// unset. `None` if `tag` is not a contact.
+fn merge_contact_tag(tag: &Tag, contact: &Contact) -> Option<Tag> {
+    match tag.as_standardized() {
+        Some(TagStandard::PublicKey {
+            public_key,
+            relay_url,
+            alias,
+            uppercase: false,
+        }) => Some(contact_tag(Contact {
+            public_key: *public_key,
+            relay_url: contact.relay_url.clone().or_else(|| relay_url.clone()),
+            alias: contact.alias.clone().or_else(|| alias.clone()),
+        })),
+        _ => None,
+    }
+}
+
+fn contacts_from_event(event: &Event) -> Vec<Contact> {
+    event
+        .tags
+        .iter()
+        .filter_map(|tag| match tag.as_standardized() {
+            Some(TagStandard::PublicKey {
+                public_key,
+                relay_url,
+                alias,
+                uppercase: false,
+            }) => Some(Contact {
+                public_key: *public_key,
+                relay_url: relay_url.clone(),
+                alias: alias.clone(),
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+fn contact_public_key(tag: &Tag) -> Option<PublicKey> {
+    match tag.as_standardized() {
+        Some(TagStandard::PublicKey {
+            public_key,
+            uppercase: false,
+            ..
+        }) => Some(*public_key),
+        _ => None,
+    }
+}
+
+async fn publish_contact_list(
+    client: &Client,
+    content: String,
+    tags: Vec<Tag>,
+) -> Result<(), Error> {
+    let builder = EventBuilder::new(Kind::ContactList, content).tags(tags);
+    let event = client.sign_event_builder(builder).await?;
+    client.send_event(&event).await?;
+    Ok(())
+}
+
+async fn add_contacts(
+    contacts: Vec<Contact>,
+    force: bool,
+    signer: Arc<dyn NostrSigner>,
     relays: Vec<String>,
 ) -> Result<(), Error> {
-    let secret_key = SecretKey::from_bech32(&secret_key_str)?;
-    let keys = Keys::new(secret_key);
-    let client = connect_client(keys, relays).await?;
-
-    let mut contacts = Vec::new();
-    for pubkey_str in pubkeys {
-        let pubkey = if let Ok(pk) = PublicKey::from_bech32(&pubkey_str) {
-            pk
+    let public_key = signer.get_public_key().await?;
+    let client = connect_client(signer, relays.clone()).await?;
+
+    let current = fetch_own_contact_list(&client, public_key, &relays, force).await?;
+    let (content, mut tags) = match current {
+        Some(event) => (event.content, event.tags.to_vec()),
+        None => (String::new(), Vec::new()),
+    };
+
+    let mut following: HashSet<PublicKey> = tags.iter().filter_map(contact_public_key).collect();
+    let mut added = 0;
+    let mut updated = 0;
+    for contact in contacts {
+        let pubkey = contact.public_key;
+        if following.insert(pubkey) {
+            tags.push(contact_tag(contact));
+            added += 1;
+        } else if contact.relay_url.is_some() || contact.alias.is_some() {
+            // Refresh the relay hint and petname of an existing follow in place
+            for tag in tags.iter_mut() {
+                if contact_public_key(tag) == Some(pubkey)
+                    && let Some(merged) = merge_contact_tag(tag, &contact)
+                    && merged != *tag
+                {
+                    *tag = merged;
+                    updated += 1;
+                }
+            }
         } else {
-            PublicKey::from_hex(&pubkey_str)?
-        };
-        contacts.push(Contact::new(pubkey));
+            println!("Already following {}", pubkey.to_bech32().unwrap());
+        }
     }
 
-    let builder = EventBuilder::contact_list(contacts);
-    let event = client.sign_event_builder(builder).await?;
-    client.send_event(&event).await?;
-    println!("Contact list updated.");
+    if added == 0 && updated == 0 {
+        println!("Contact list unchanged.");
+    } else {
+        publish_contact_list(&client, content, tags).await?;
+        println!(
+            "Contact list updated: {added} added, {updated} updated, {} total.",
+            following.len()
+        );
+    }
 
     client.shutdown().await;
     Ok(())
 }
 
-async fn get_contact_list(pubkey: String, relays: Vec<String>) -> Result<(), Error> {
-    if relays.is_empty() {
-        return Err(Error::Message(
-            "No relays provided in args or config".to_string(),
-        ));
-    }
-    let pubkey = if let Ok(pk) = PublicKey::from_bech32(&pubkey) {
-        pk
+async fn remove_contacts(
+    pubkeys: Vec<String>,
+    force: bool,
+    signer: Arc<dyn NostrSigner>,
+    relays: Vec<String>,
+) -> Result<(), Error> {
+    let to_remove = pubkeys
+        .iter()
+        .map(|s| parse_public_key(s))
+        .collect::<Result<HashSet<_>, _>>()?;
+
+    let public_key = signer.get_public_key().await?;
+    let client = connect_client(signer, relays.clone()).await?;
+
+    let current = fetch_own_contact_list(&client, public_key, &relays, force).await?;
+    let (content, tags) = match current {
+        Some(event) => (event.content, event.tags.to_vec()),
+        None => (String::new(), Vec::new()),
+    };
+
+    let before = tags.iter().filter_map(contact_public_key).count();
+    let tags: Vec<Tag> = tags
+        .into_iter()
+        .filter(|tag| !contact_public_key(tag).is_some_and(|pk| to_remove.contains(&pk)))
+        .collect();
+    let after = tags.iter().filter_map(contact_public_key).count();
+    let removed = before - after;
+
+    if removed == 0 {
+        println!("None of the given public keys are in your contact list.");
     } else {
-        PublicKey::from_hex(&pubkey)?
+        publish_contact_list(&client, content, tags).await?;
+        println!("Contact list updated: {removed} removed, {after} total.");
+    }
+
+    client.shutdown().await;
+    Ok(())
+}
+
+async fn replace_contacts(
+    contacts: Vec<Contact>,
+    force: bool,
+    signer: Arc<dyn NostrSigner>,
+    relays: Vec<String>,
+) -> Result<(), Error> {
+    let public_key = signer.get_public_key().await?;
+    let client = connect_client(signer, relays.clone()).await?;
+
+    // The current list is only needed to carry over the `content` field and report what changes
+    let current = fetch_own_contact_list(&client, public_key, &relays, force).await?;
+    let (content, current_contacts) = match &current {
+        Some(event) => (event.content.clone(), contacts_from_event(event)),
+        None => (String::new(), Vec::new()),
     };
 
+    let diff = ContactDiff::new(&current_contacts, &contacts);
+    let total = contacts.len();
+    let tags: Vec<Tag> = contacts.into_iter().map(contact_tag).collect();
+    publish_contact_list(&client, content, tags).await?;
+    println!(
+        "Contact list replaced: {} added, {} removed, {} updated, {total} total.",
+        diff.added.len(),
+        diff.removed.len(),
+        diff.changed.len()
+    );
+
+    client.shutdown().await;
+    Ok(())
+}
+
+async fn export_contacts(
+    pubkey: PublicKey,
+    file: String,
+    format: Option<ContactFileFormat>,
+    relays: Vec<String>,
+) -> Result<(), Error> {
+    let format = resolve_format(&file, format)?;
+
     let keys = Keys::generate();
-    let client = Client::new(keys);
+    let client = connect_client(keys, relays.clone()).await?;
+    let event = fetch_contact_list_event(&client, pubkey, &relays).await?;
+    client.shutdown().await;
 
-    let relay_urls: Vec<&str> = relays.iter().map(|s| s.as_str()).collect();
+    let event = event.ok_or(Error::Message("Contact list not found.".to_string()))?;
+    let contacts = contacts_from_event(&event);
+    let output = match format {
+        ContactFileFormat::Json => {
+            let snapshot = ContactListFile {
+                pubkey: Some(pubkey.to_bech32().unwrap()),
+                created_at: Some(event.created_at.as_u64()),
+                contacts: contacts.iter().map(ContactEntry::from).collect(),
+            };
+            serde_json::to_string_pretty(&snapshot)?
+        }
+        ContactFileFormat::Csv => contacts_to_csv(&contacts),
+    };
+    std::fs::write(&file, output)?;
+    println!("Exported {} contacts to {file}", contacts.len());
+    Ok(())
+}
 
-    let filter = Filter::new()
-        .author(pubkey)
-        .kind(Kind::ContactList)
-        .limit(1);
+async fn diff_contacts(
+    own_pubkey: PublicKey,
+    file: Option<String>,
+    pubkey: Option<String>,
+    format: Option<ContactFileFormat>,
+    relays: Vec<String>,
+) -> Result<(), Error> {
+    let keys = Keys::generate();
+    let client = connect_client(keys, relays.clone()).await?;
+
+    let ours = fetch_contact_list_event(&client, own_pubkey, &relays)
+        .await?
+        .map(|event| contacts_from_event(&event))
+        .unwrap_or_default();
+    let theirs = match (file, pubkey) {
+        (Some(file), _) => read_contact_file(&file, format)?,
+        (None, Some(pubkey)) => {
+            fetch_contact_list_event(&client, parse_public_key(&pubkey)?, &relays)
+                .await?
+                .map(|event| contacts_from_event(&event))
+                .ok_or(Error::Message(
+                    "Contact list not found for the given public key.".to_string(),
+                ))?
+        }
+        (None, None) => unreachable!("clap requires --file or --pubkey"),
+    };
+    client.shutdown().await;
+
+    let diff = ContactDiff::new(&ours, &theirs);
+    for contact in &diff.added {
+        println!("{}", format!("+ {}", describe_contact(contact)).green());
+    }
+    for contact in &diff.removed {
+        println!("{}", format!("- {}", describe_contact(contact)).red());
+    }
+    for contact in &diff.changed {
+        println!("{}", format!("~ {}", describe_contact(contact)).yellow());
+    }
+    println!(
+        "{} added, {} removed, {} changed ({} in yours, {} in theirs)",
+        diff.added.len(),
+        diff.removed.len(),
+        diff.changed.len(),
+        ours.len(),
+        theirs.len()
+    );
+    Ok(())
+}
+
+async fn contact_history(pubkey: PublicKey, relays: Vec<String>) -> Result<(), Error> {
+    let keys = Keys::generate();
+    let client = connect_client(keys, relays.clone()).await?;
 
+    let filter = Filter::new().author(pubkey).kind(Kind::ContactList);
     let timeout = Duration::from_secs(10);
     let events = client
-        .fetch_events_from(relay_urls, filter, timeout)
+        .fetch_events_from(relays.iter().map(|s| s.as_str()), filter, timeout)
         .await?;
+    client.shutdown().await;
+
+    if events.is_empty() {
+        println!("{}", "No contact list versions found.".red());
+        return Ok(());
+    }
+
+    // Events come newest first; walk them oldest first to diff each version with its predecessor
+    let versions: Vec<Event> = events.into_iter().collect();
+    let mut rows = Vec::with_capacity(versions.len());
+    let mut previous: Option<Vec<Contact>> = None;
+    for event in versions.iter().rev() {
+        let contacts = contacts_from_event(event);
+        let (added, removed) = match &previous {
+            Some(prev) => {
+                let diff = ContactDiff::new(prev, &contacts);
+                (diff.added.len().to_string(), diff.removed.len().to_string())
+            }
+            None => ("-".to_string(), "-".to_string()),
+        };
+        rows.push(vec![
+            Cell::new(event.id.to_bech32().unwrap()),
+            Cell::new(event.created_at.to_human_datetime()),
+            Cell::new(contacts.len()),
+            Cell::new(format!("+{added}")),
+            Cell::new(format!("-{removed}")),
+        ]);
+        previous = Some(contacts);
+    }
 
-    if let Some(event) = events.first() {
-        println!("{:#?}", event.tags);
+    let mut table = Table::new();
+    table.load_preset(UTF8_FULL).set_header(vec![
+        "Event ID",
+        "Created At",
+        "Follows",
+        "Added",
+        "Removed",
+    ]);
+    for row in rows.into_iter().rev() {
+        table.add_row(row);
+    }
+    println!("{table}");
+    println!("{} versions found", versions.len());
+    Ok(())
+}
+
+async fn restore_contacts(
+    event_id: String,
+    signer: Arc<dyn NostrSigner>,
+    relays: Vec<String>,
+) -> Result<(), Error> {
+    let event_id = if let Ok(id) = EventId::from_bech32(&event_id) {
+        id
     } else {
-        println!("Contact list not found.");
+        EventId::from_hex(&event_id)?
+    };
+
+    let public_key = signer.get_public_key().await?;
+    let client = connect_client(signer, relays.clone()).await?;
+
+    let filter = Filter::new().id(event_id);
+    let timeout = Duration::from_secs(10);
+    let events = client
+        .fetch_events_from(relays.iter().map(|s| s.as_str()), filter, timeout)
+        .await?;
+    let Some(old) = events.first_owned() else {
+        client.shutdown().await;
+        return Err(Error::Message("Event not found.".to_string()));
+    };
+    if old.kind != Kind::ContactList {
+        client.shutdown().await;
+        return Err(Error::Message(format!(
+            "Event is kind {}, not a contact list (kind 3).",
+            old.kind
+        )));
+    }
+    if old.pubkey != public_key {
+        println!(
+            "{}",
+            "Warning: this contact list was published by a different public key.".yellow()
+        );
+    }
+
+    let current = fetch_contact_list_event(&client, public_key, &relays)
+        .await?
+        .map(|event| contacts_from_event(&event))
+        .unwrap_or_default();
+    let restored = contacts_from_event(&old);
+    let diff = ContactDiff::new(&current, &restored);
+    println!(
+        "Restoring version from {}: {} follows ({} added, {} removed, {} changed).",
+        old.created_at.to_human_datetime(),
+        restored.len(),
+        diff.added.len(),
+        diff.removed.len(),
+        diff.changed.len()
+    );
+
+    if Confirm::with_theme(&ColorfulTheme::default())
+        .with_prompt("Do you want to publish this contact list?")
+        .default(false)
+        .interact()?
+    {
+        publish_contact_list(&client, old.content, old.tags.to_vec()).await?;
+        println!("Contact list restored.");
+    } else {
+        println!("Restore cancelled.");
     }
 
     client.shutdown().await;
     Ok(())
 }
+
+fn describe_contact(contact: &Contact) -> String {
+    let mut out = contact.public_key.to_bech32().unwrap();
+    if let Some(relay) = &contact.relay_url {
+        out.push_str(&format!(" @{relay}"));
+    }
+    if let Some(alias) = &contact.alias {
+        out.push_str(&format!(" #{alias}"));
+    }
+    out
+}
+
+/// Differences going from one contact list to another.
+#[derive(Default)]
+struct ContactDiff {
+    /// Contacts only in the new list
+    added: Vec<Contact>,
+    /// Contacts only in the old list
+    removed: Vec<Contact>,
+    /// Contacts in both lists whose relay hint or petname differ (as in the new list)
+    changed: Vec<Contact>,
+}
+
+impl ContactDiff {
+    fn new(old: &[Contact], new: &[Contact]) -> Self {
+        let old_by_pk: HashMap<PublicKey, &Contact> =
+            old.iter().map(|c| (c.public_key, c)).collect();
+        let new_pks: HashSet<PublicKey> = new.iter().map(|c| c.public_key).collect();
+
+        let mut diff = Self::default();
+        for contact in new {
+            match old_by_pk.get(&contact.public_key) {
+                None => diff.added.push(contact.clone()),
+                Some(old) if *old != contact => diff.changed.push(contact.clone()),
+                Some(_) => {}
+            }
+        }
+        diff.removed = old
+            .iter()
+            .filter(|c| !new_pks.contains(&c.public_key))
+            .cloned()
+            .collect();
+        diff
+    }
+}
+
+/// JSON snapshot of a contact list.
+#[derive(Serialize, Deserialize)]
+struct ContactListFile {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pubkey: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    created_at: Option<u64>,
+    contacts: Vec<ContactEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ContactEntry {
+    pubkey: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    relay: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    petname: Option<String>,
+}
+
+impl From<&Contact> for ContactEntry {
+    fn from(contact: &Contact) -> Self {
+        Self {
+            pubkey: contact.public_key.to_bech32().unwrap(),
+            relay: contact.relay_url.as_ref().map(|r| r.to_string()),
+            petname: contact.alias.clone(),
+        }
+    }
+}
+
+impl TryFrom<ContactEntry> for Contact {
+    type Error = Error;
+
+    fn try_from(entry: ContactEntry) -> Result<Self, Self::Error> {
+        let mut contact = Contact::new(parse_public_key(&entry.pubkey)?);
+        contact.relay_url = entry
+            .relay
+            .filter(|r| !r.is_empty())
+            .map(|r| RelayUrl::parse(&r))
+            .transpose()?;
+        contact.alias = entry.petname.filter(|p| !p.is_empty());
+        Ok(contact)
+    }
+}
+
+fn resolve_format(
+    file: &str,
+    format: Option<ContactFileFormat>,
+) -> Result<ContactFileFormat, Error> {
+    if let Some(format) = format {
+        return Ok(format);
+    }
+    match Path::new(file).extension().and_then(|e| e.to_str()) {
+        Some(ext) if ext.eq_ignore_ascii_case("json") => Ok(ContactFileFormat::Json),
+        Some(ext) if ext.eq_ignore_ascii_case("csv") => Ok(ContactFileFormat::Csv),
+        _ => Err(Error::Message(format!(
+            "Cannot infer the format of {file}. Use --format json|csv."
+        ))),
+    }
+}
+
+fn read_contact_file(file: &str, format: Option<ContactFileFormat>) -> Result<Vec<Contact>, Error> {
+    let format = resolve_format(file, format)?;
+    let content = std::fs::read_to_string(file)?;
+    match format {
+        ContactFileFormat::Json => {
+            let snapshot: ContactListFile = serde_json::from_str(&content)?;
+            snapshot
+                .contacts
+                .into_iter()
+                .map(Contact::try_from)
+                .collect()
+        }
+        ContactFileFormat::Csv => contacts_from_csv(&content),
+    }
+}
+
+const CSV_HEADER: &str = "pubkey,relay,petname";
+
+fn contacts_to_csv(contacts: &[Contact]) -> String {
+    let mut out = format!("{CSV_HEADER}\n");
+    for contact in contacts {
+        let entry = ContactEntry::from(contact);
+        out.push_str(&format!(
+            "{},{},{}\n",
+            csv_field(&entry.pubkey),
+            csv_field(entry.relay.as_deref().unwrap_or_default()),
+            csv_field(entry.petname.as_deref().unwrap_or_default())
+        ));
+    }
+    out
+}
+
+fn contacts_from_csv(content: &str) -> Result<Vec<Contact>, Error> {
+    parse_csv(content)
+        .into_iter()
+        .filter(|record| {
+            let line = record.join(",");
+            !line.trim().is_empty() && line.trim() != CSV_HEADER
+        })
+        .map(|record| {
+            let mut fields = record.into_iter();
+            let entry = ContactEntry {
+                pubkey: fields.next().unwrap_or_default(),
+                relay: fields.next(),
+                petname: fields.next(),
+            };
+            Contact::try_from(entry)
+        })
+        .collect()
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Split CSV into records of fields. Quoted fields may contain commas, quotes and line breaks.
+fn parse_csv(content: &str) -> Vec<Vec<String>> {
+    let mut records = Vec::new();
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = content.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => fields.push(std::mem::take(&mut field)),
+            '\r' if !in_quotes && chars.peek() == Some(&'\n') => {}
+            '\n' if !in_quotes => {
+                fields.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut fields));
+            }
+            c => field.push(c),
+        }
+    }
+    if !field.is_empty() || !fields.is_empty() {
+        fields.push(field);
+        records.push(fields);
+    }
+    records
+}
+
+/// Number of authors requested per kind-0 filter, to stay below common relay limits.
+const METADATA_BATCH_SIZE: usize = 250;
+
+/// Fetch the latest kind-0 metadata for each of `pubkeys`.
+async fn fetch_profiles(
+    client: &Client,
+    pubkeys: &[PublicKey],
+    relays: &[String],
+) -> Result<HashMap<PublicKey, Metadata>, Error> {
+    let mut profiles = HashMap::new();
+    for batch in pubkeys.chunks(METADATA_BATCH_SIZE) {
+        let filter = Filter::new()
+            .authors(batch.iter().copied())
+            .kind(Kind::Metadata);
+        let timeout = Duration::from_secs(10);
+        let events = client
+            .fetch_events_from(relays.iter().map(|s| s.as_str()), filter, timeout)
+            .await?;
+        // Events are sorted newest first, so keep the first one seen per author
+        for event in events {
+            if profiles.contains_key(&event.pubkey) {
+                continue;
+            }
+            if let Ok(metadata) = Metadata::from_json(&event.content) {
+                profiles.insert(event.pubkey, metadata);
+            }
+        }
+    }
+    Ok(profiles)
+}
+
+#[derive(Serialize)]
+struct ContactRow {
+    npub: String,
+    petname: Option<String>,
+    relay: Option<String>,
+    display_name: Option<String>,
+    nip05: Option<String>,
+}
+
+impl ContactRow {
+    fn new(contact: &Contact, metadata: Option<&Metadata>) -> Self {
+        Self {
+            npub: contact.public_key.to_bech32().unwrap(),
+            petname: contact.alias.clone(),
+            relay: contact.relay_url.as_ref().map(|r| r.to_string()),
+            display_name: metadata
+                .and_then(|m| m.display_name.clone().or_else(|| m.name.clone()))
+                .filter(|s| !s.is_empty()),
+            nip05: metadata
+                .and_then(|m| m.nip05.clone())
+                .filter(|s| !s.is_empty()),
+        }
+    }
+}
+
+async fn get_contact_list(pubkey: String, json: bool, relays: Vec<String>) -> Result<(), Error> {
+    let pubkey = parse_public_key(&pubkey)?;
+
+    let keys = Keys::generate();
+    let client = connect_client(keys, relays.clone()).await?;
+
+    let spinner = ProgressBar::new_spinner();
+    spinner.set_style(
+        ProgressStyle::default_spinner()
+            .template("{spinner:.blue} {msg}")
+            .unwrap()
+            .tick_strings(&["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"]),
+    );
+    spinner.set_message("Fetching contact list...");
+    spinner.enable_steady_tick(Duration::from_millis(100));
+
+    let Some(event) = fetch_contact_list_event(&client, pubkey, &relays).await? else {
+        spinner.finish_and_clear();
+        println!("{}", "Contact list not found.".red());
+        client.shutdown().await;
+        return Ok(());
+    };
+
+    let contacts = contacts_from_event(&event);
+    spinner.set_message("Fetching profiles...");
+    let pubkeys: Vec<PublicKey> = contacts.iter().map(|c| c.public_key).collect();
+    let profiles = fetch_profiles(&client, &pubkeys, &relays).await?;
+    spinner.finish_and_clear();
+
+    let rows: Vec<ContactRow> = contacts
+        .iter()
+        .map(|c| ContactRow::new(c, profiles.get(&c.public_key)))
+        .collect();
+
+    if json {
+        for row in &rows {
+            println!("{}", serde_json::to_string(row)?);
+        }
+    } else {
+        let mut table = Table::new();
+        table.load_preset(UTF8_FULL).set_header(vec![
+            "Public Key",
+            "Petname",
+            "Relay",
+            "Display Name",
+            "NIP-05",
+        ]);
+        for row in rows {
+            table.add_row(vec![
+                Cell::new(row.npub),
+                Cell::new(row.petname.unwrap_or_default()),
+                Cell::new(row.relay.unwrap_or_default()),
+                Cell::new(row.display_name.unwrap_or_default()),
+                Cell::new(row.nip05.unwrap_or_default()),
+            ]);
+        }
+        println!("{table}");
+        println!("{} contacts", contacts.len());
+    }
+
+    client.shutdown().await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NPUB: &str = "npub1sg6plzptd64u62a878hep2kev88swjh3tw00gjsfl8f237lmu63q0uf63m";
+
+    #[test]
+    fn test_parse_contact_with_relay_and_petname() {
+        let contact = parse_contact(&format!("{NPUB}@wss://relay.example.com#alice")).unwrap();
+        assert_eq!(contact.public_key, PublicKey::from_bech32(NPUB).unwrap());
+        assert_eq!(
+            contact.relay_url,
+            Some(RelayUrl::parse("wss://relay.example.com").unwrap())
+        );
+        assert_eq!(contact.alias.as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn test_csv_round_trip() {
+        let mut contact = Contact::new(PublicKey::from_bech32(NPUB).unwrap());
+        contact.alias = Some("alice, \"the\" admin\r\nof the\nrelay".to_string());
+        let csv = contacts_to_csv(&[contact.clone(), Contact::new(contact.public_key)]);
+        let parsed = contacts_from_csv(&csv).unwrap();
+        assert_eq!(
+            parsed,
+            vec![contact.clone(), Contact::new(contact.public_key)]
+        );
+
+        let crlf = contacts_from_csv(&format!("{CSV_HEADER}\r\n{NPUB},,bob\r\n")).unwrap();
+        assert_eq!(crlf[0].alias.as_deref(), Some("bob"));
+    }
+
+    #[test]
+    fn test_merge_contact_tag_keeps_unset_fields() {
+        let public_key = PublicKey::from_bech32(NPUB).unwrap();
+        let existing =
+            contact_tag(parse_contact(&format!("{NPUB}@wss://old.example.com#alice")).unwrap());
+
+        let relay_only = parse_contact(&format!("{NPUB}@wss://new.example.com")).unwrap();
+        let merged = merge_contact_tag(&existing, &relay_only).unwrap();
+        assert_eq!(
+            merged,
+            contact_tag(Contact {
+                public_key,
+                relay_url: Some(RelayUrl::parse("wss://new.example.com").unwrap()),
+                alias: Some("alice".to_string()),
+            })
+        );
+
+        let petname_only = parse_contact(&format!("{NPUB}#bob")).unwrap();
+        let merged = merge_contact_tag(&merged, &petname_only).unwrap();
+        assert_eq!(
+            merged,
+            contact_tag(Contact {
+                public_key,
+                relay_url: Some(RelayUrl::parse("wss://new.example.com").unwrap()),
+                alias: Some("bob".to_string()),
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_contact_bare_pubkey() {
+        let contact = parse_contact(NPUB).unwrap();
+        assert!(contact.relay_url.is_none());
+        assert!(contact.alias.is_none());
+    }
+}
@@ -1,17 +1,18 @@
 use crate::cli::CommonOptions;
-use crate::cli::common::{connect_client, get_relays, get_secret_key};
+use crate::cli::common::{connect_client, get_relays, get_signer};
 use crate::config::load_config;
 use clap::{Parser, Subcommand};
 use colored::*;
 use comfy_table::{Cell, CellAlignment, Table, presets::UTF8_FULL};
 use dialoguer::{Confirm, Input, theme::ColorfulTheme};
 use indicatif::{ProgressBar, ProgressStyle};
-use nostr::nips::nip44;
 use nostr::prelude::{FromBech32, ToBech32};
-use nostr::{EventBuilder, Keys, SecretKey};
+use nostr::{EventBuilder, Keys};
 use nostr_sdk::nips::nip09::EventDeletionRequest;
 use nostr_sdk::prelude::*;
 use serde_json;
+use std::collections::HashSet;
+use std::sync::Arc;
 use std::time::Duration;
 
 #[derive(Parser, Clone)]
@@ -31,6 +32,9 @@ enum EventSubcommand {
         /// Recipient public key for gift wrap (NIP-59)
         #[clap(long)]
         gift_wrap_recipient: Option<String>,
+        /// Event to reply to (note, nevent or hex id), threaded per NIP-10
+        #[clap(long, conflicts_with = "gift_wrap_recipient")]
+        reply_to: Option<String>,
     },
     /// Get an event by id
     Get {
@@ -75,6 +79,25 @@ enum EventSubcommand {
     },
     /// Edit profile metadata (NIP-01)
     EditProfile,
+    /// React to an event (NIP-25)
+    React {
+        /// Event to react to (note, nevent or hex id)
+        id: String,
+        /// `+` to like, `-` to dislike, an emoji or a `:shortcode:` custom emoji
+        #[clap(long, default_value = "+")]
+        content: String,
+        /// Image URL of a `:shortcode:` custom emoji (NIP-30)
+        #[clap(long)]
+        emoji_url: Option<String>,
+    },
+    /// Repost an event (NIP-18)
+    Repost {
+        /// Event to repost (note, nevent or hex id)
+        id: String,
+        /// Text for a quote repost, posted as a text note quoting the event
+        #[clap(long)]
+        quote: Option<String>,
+    },
 }
 
 use crate::error::Error;
@@ -87,29 +110,28 @@ pub async fn handle_event_command(command: EventCommand) -> Result<(), Error> {
         EventSubcommand::CreateTextNote {
             content,
             gift_wrap_recipient,
+            reply_to,
         } => {
-            let secret_key_str = get_secret_key(&command.common, &config)?;
-            create_text_note(content, gift_wrap_recipient, secret_key_str, relays).await?;
+            let signer = get_signer(&command.common, &config)?;
+            create_text_note(content, gift_wrap_recipient, reply_to, signer, relays).await?;
         }
         EventSubcommand::Get { id } => {
             get_event(id, relays).await?;
         }
         EventSubcommand::Delete { event_id } => {
-            let secret_key_str = get_secret_key(&command.common, &config)?;
-            delete_event(event_id, secret_key_str, relays).await?;
+            let signer = get_signer(&command.common, &config)?;
+            delete_event(event_id, signer, relays).await?;
         }
         EventSubcommand::EncryptPayload { recipient, content } => {
-            let secret_key_str = get_secret_key(&command.common, &config)?;
-            let sk = SecretKey::from_bech32(&secret_key_str)?;
+            let signer = get_signer(&command.common, &config)?;
             let pk = PublicKey::from_bech32(&recipient)?;
-            let encrypted = nip44::encrypt(&sk, &pk, &content, nip44::Version::default())?;
+            let encrypted = signer.nip44_encrypt(&pk, &content).await?;
             println!("{encrypted}");
         }
         EventSubcommand::DecryptPayload { sender, content } => {
-            let secret_key_str = get_secret_key(&command.common, &config)?;
-            let sk = SecretKey::from_bech32(&secret_key_str)?;
+            let signer = get_signer(&command.common, &config)?;
             let pk = PublicKey::from_bech32(&sender)?;
-            let decrypted = nip44::decrypt(&sk, &pk, &content)?;
+            let decrypted = signer.nip44_decrypt(&pk, &content).await?;
             println!("{decrypted}");
         }
         EventSubcommand::CreateLongFormPost {
@@ -118,13 +140,25 @@ pub async fn handle_event_command(command: EventCommand) -> Result<(), Error> {
             summary,
             d_identifier,
         } => {
-            let secret_key_str = get_secret_key(&command.common, &config)?;
-            create_long_form_post(file, title, summary, d_identifier, secret_key_str, relays)
+            let signer = get_signer(&command.common, &config)?;
+            create_long_form_post(file, title, summary, d_identifier, signer, relays)
                 .await?;
         }
         EventSubcommand::EditProfile => {
-            let secret_key_str = get_secret_key(&command.common, &config)?;
-            edit_profile(secret_key_str, relays).await?;
+            let signer = get_signer(&command.common, &config)?;
+            edit_profile(signer, relays).await?;
+        }
+        EventSubcommand::React {
+            id,
+            content,
+            emoji_url,
+        } => {
+            let signer = get_signer(&command.common, &config)?;
+            react(id, content, emoji_url, signer, relays).await?;
+        }
+        EventSubcommand::Repost { id, quote } => {
+            let signer = get_signer(&command.common, &config)?;
+            repost(id, quote, signer, relays).await?;
         }
     }
     Ok(())
@@ -133,19 +167,23 @@ pub async fn handle_event_command(command: EventCommand) -> Result<(), Error> {
 async fn create_text_note(
     content: String,
     gift_wrap_recipient: Option<String>,
-    secret_key_str: String,
+    reply_to: Option<String>,
+    signer: Arc<dyn NostrSigner>,
     relays: Vec<String>,
 ) -> Result<(), Error> {
-    let secret_key = SecretKey::from_bech32(&secret_key_str)?;
-    let keys = Keys::new(secret_key);
-    let client = connect_client(keys.clone(), relays).await?;
+    let client = connect_client(signer.clone(), relays).await?;
 
-    let builder = EventBuilder::text_note(&content);
+    let mut builder = EventBuilder::text_note(&content);
+    if let Some(reply_to) = reply_to {
+        let (parent, relay_url) = fetch_referenced_event(&client, &reply_to).await?;
+        let tags = reply_tags(&parent, relay_url, signer.get_public_key().await?);
+        builder = builder.tags(tags);
+    }
 
     let event_to_send = if let Some(recipient_str) = gift_wrap_recipient {
         let recipient_pk = PublicKey::from_bech32(&recipient_str)?;
-        let rumor = builder.build(keys.public_key());
-        EventBuilder::gift_wrap(&keys, &recipient_pk, rumor, []).await?
+        let rumor = builder.build(signer.get_public_key().await?);
+        EventBuilder::gift_wrap(&signer, &recipient_pk, rumor, []).await?
     } else {
         client.sign_event_builder(builder).await?
     };
@@ -157,9 +195,142 @@ async fn create_text_note(
     Ok(())
 }
 
-pub async fn edit_profile(secret_key_str: String, relays: Vec<String>) -> Result<(), Error> {
-    let keys = Keys::new(SecretKey::from_bech32(&secret_key_str)?);
-    let client = connect_client(keys.clone(), relays.clone()).await?;
+/// Parse a note, nevent or hex event id, optionally as a `nostr:` URI, with any relay hints.
+pub fn parse_event_reference(input: &str) -> Result<(EventId, Vec<RelayUrl>), Error> {
+    let input = input.trim().trim_start_matches("nostr:");
+    if let Ok(event_id) = EventId::from_hex(input) {
+        return Ok((event_id, Vec::new()));
+    }
+    match Nip19::from_bech32(input)? {
+        Nip19::EventId(event_id) => Ok((event_id, Vec::new())),
+        Nip19::Event(event) => Ok((event.event_id, event.relays)),
+        _ => Err(Error::Message(
+            "Expected a note, nevent or event id".to_string(),
+        )),
+    }
+}
+
+/// Fetch the event `reference` points to, along with the relay it was found on.
+///
+/// Relay hints from an nevent are added as read relays for the lookup.
+pub async fn fetch_referenced_event(
+    client: &Client,
+    reference: &str,
+) -> Result<(Event, RelayUrl), Error> {
+    let (event_id, hints) = parse_event_reference(reference)?;
+    for hint in hints {
+        client.add_read_relay(hint).await?;
+    }
+    client.connect().await;
+
+    let relay_count = client.relays().await.len();
+    let mut notifications = client.notifications();
+    let subscription_id = client
+        .subscribe(Filter::new().id(event_id), None)
+        .await?
+        .val;
+    let found = tokio::time::timeout(Duration::from_secs(10), async {
+        let mut finished = 0;
+        while let Ok(notification) = notifications.recv().await {
+            match notification {
+                RelayPoolNotification::Event {
+                    relay_url,
+                    subscription_id: id,
+                    event,
+                } if id == subscription_id => return Some((*event, relay_url)),
+                RelayPoolNotification::Message {
+                    message: RelayMessage::EndOfStoredEvents(id),
+                    ..
+                } if *id == subscription_id => {
+                    finished += 1;
+                    if finished == relay_count {
+                        break;
+                    }
+                }
+                _ => {}
+            }
+        }
+        None
+    })
+    .await
+    .ok()
+    .flatten();
+    client.unsubscribe(&subscription_id).await;
+
+    found.ok_or_else(|| {
+        Error::Message(format!(
+            "Event {} not found.",
+            event_id.to_bech32().unwrap()
+        ))
+    })
+}
+
+/// NIP-10 tags for a reply to `parent`: marked `e` tags for the thread root and the parent, and
+/// `p` tags for the parent author and everyone already in the thread except `own_public_key`.
+pub fn reply_tags(parent: &Event, relay_url: RelayUrl, own_public_key: PublicKey) -> Vec<Tag> {
+    let references: Vec<_> = parent
+        .tags
+        .filter_standardized(TagKind::e())
+        .filter_map(|tag| match tag {
+            TagStandard::Event {
+                event_id,
+                relay_url,
+                marker,
+                public_key,
+                ..
+            } => Some((*event_id, relay_url.clone(), *marker, *public_key)),
+            _ => None,
+        })
+        .collect();
+    // Marked tags name the root explicitly; in the older positional scheme it is the first `e` tag
+    let root = references
+        .iter()
+        .find(|(_, _, marker, _)| *marker == Some(Marker::Root))
+        .or_else(|| references.iter().find(|(_, _, marker, _)| marker.is_none()))
+        .cloned();
+
+    let mut tags = Vec::new();
+    let mut public_keys = vec![parent.pubkey];
+    match root {
+        Some((event_id, root_relay_url, _, public_key)) => {
+            tags.push(Tag::from_standardized_without_cell(TagStandard::Event {
+                event_id,
+                relay_url: root_relay_url,
+                marker: Some(Marker::Root),
+                public_key,
+                uppercase: false,
+            }));
+            tags.push(Tag::from_standardized_without_cell(TagStandard::Event {
+                event_id: parent.id,
+                relay_url: Some(relay_url),
+                marker: Some(Marker::Reply),
+                public_key: Some(parent.pubkey),
+                uppercase: false,
+            }));
+            public_keys.extend(public_key);
+        }
+        None => tags.push(Tag::from_standardized_without_cell(TagStandard::Event {
+            event_id: parent.id,
+            relay_url: Some(relay_url),
+            marker: Some(Marker::Root),
+            public_key: Some(parent.pubkey),
+            uppercase: false,
+        })),
+    }
+
+    public_keys.extend(parent.tags.public_keys().copied());
+    let mut seen = HashSet::new();
+    for public_key in public_keys {
+        if public_key != own_public_key && seen.insert(public_key) {
+            tags.push(Tag::public_key(public_key));
+        }
+    }
+    tags
+}
+
+pub async fn edit_profile(signer: Arc<dyn NostrSigner>, relays: Vec<String>) -> Result<(), Error> {
+    let public_key = signer.get_public_key().await?;
+    let client = connect_client(signer, relays.clone()).await?;
 
     let spinner = ProgressBar::new_spinner();
     spinner.set_style(
@@ -171,7 +342,7 @@ pub async fn edit_profile(secret_key_str: String, relays: Vec<String>) -> Result
     spinner.enable_steady_tick(Duration::from_millis(100));
 
     let filter = Filter::new()
-        .author(keys.public_key())
+        .author(public_key)
         .kind(Kind::Metadata)
         .limit(1);
     let timeout = Duration::from_secs(10);
@@ -316,11 +487,10 @@ async fn create_long_form_post(
     title: Option<String>,
     summary: Option<String>,
     d_identifier: Option<String>,
-    secret_key_str: String,
+    signer: Arc<dyn NostrSigner>,
     relays: Vec<String>,
 ) -> Result<(), Error> {
-    let keys = Keys::new(SecretKey::from_bech32(&secret_key_str)?);
-    let client = connect_client(keys.clone(), relays).await?;
+    let client = connect_client(signer, relays).await?;
 
     let content = std::fs::read_to_string(&file)?;
 
@@ -359,11 +529,10 @@ async fn create_long_form_post(
 
 async fn delete_event(
     event_id_str: String,
-    secret_key_str: String,
+    signer: Arc<dyn NostrSigner>,
     relays: Vec<String>,
 ) -> Result<(), Error> {
-    let keys = Keys::new(SecretKey::from_bech32(&secret_key_str)?);
-    let client = connect_client(keys, relays).await?;
+    let client = connect_client(signer, relays).await?;
 
     let event_id_to_delete = if let Ok(id) = EventId::from_bech32(&event_id_str) {
         id
@@ -388,6 +557,93 @@ async fn delete_event(
     Ok(())
 }
 
+async fn react(
+    id: String,
+    content: String,
+    emoji_url: Option<String>,
+    signer: Arc<dyn NostrSigner>,
+    relays: Vec<String>,
+) -> Result<(), Error> {
+    let client = connect_client(signer, relays).await?;
+
+    let (target, _) = fetch_referenced_event(&client, &id).await?;
+    let mut builder = EventBuilder::reaction(&target, &content);
+    if let Some(tag) = emoji_tag(&content, emoji_url)? {
+        builder = builder.tag(tag);
+    }
+    let signed_event = client.sign_event_builder(builder).await?;
+    let event_id = client.send_event(&signed_event).await?;
+    println!("Reaction sent with id: {}", event_id.to_bech32().unwrap());
+
+    client.shutdown().await;
+    Ok(())
+}
+
+/// NIP-30 `emoji` tag for a `:shortcode:` reaction. Other reactions need no tag.
+fn emoji_tag(content: &str, emoji_url: Option<String>) -> Result<Option<Tag>, Error> {
+    let shortcode = content
+        .strip_prefix(':')
+        .and_then(|content| content.strip_suffix(':'))
+        .filter(|shortcode| {
+            !shortcode.is_empty()
+                && shortcode
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        });
+    match (shortcode, emoji_url) {
+        (Some(shortcode), Some(url)) => Ok(Some(Tag::from_standardized_without_cell(
+            TagStandard::Emoji {
+                shortcode: shortcode.to_string(),
+                url: Url::parse(&url)?,
+            },
+        ))),
+        (Some(shortcode), None) => Err(Error::Message(format!(
+            "The custom emoji :{shortcode}: needs its image URL (--emoji-url)"
+        ))),
+        (None, Some(_)) => Err(Error::Message(
+            "--emoji-url needs a :shortcode: reaction".to_string(),
+        )),
+        (None, None) => Ok(None),
+    }
+}
+
+async fn repost(
+    id: String,
+    quote: Option<String>,
+    signer: Arc<dyn NostrSigner>,
+    relays: Vec<String>,
+) -> Result<(), Error> {
+    let client = connect_client(signer, relays).await?;
+
+    let (target, relay_url) = fetch_referenced_event(&client, &id).await?;
+    let builder = match quote {
+        // Quote reposts are text notes that mention the event and carry a `q` tag
+        Some(text) => {
+            let nevent = Nip19Event::new(target.id)
+                .author(target.pubkey)
+                .kind(target.kind)
+                .relays([relay_url.clone()])
+                .to_bech32()?;
+            EventBuilder::text_note(format!("{text}\n\nnostr:{nevent}")).tags([
+                Tag::from_standardized_without_cell(TagStandard::Quote {
+                    event_id: target.id,
+                    relay_url: Some(relay_url),
+                    public_key: Some(target.pubkey),
+                }),
+                Tag::public_key(target.pubkey),
+            ])
+        }
+        // Kind 6 for text notes, kind 16 with a `k` tag otherwise, both embedding the event
+        None => EventBuilder::repost(&target, Some(relay_url)),
+    };
+    let signed_event = client.sign_event_builder(builder).await?;
+    let event_id = client.send_event(&signed_event).await?;
+    println!("Repost sent with id: {}", event_id.to_bech32().unwrap());
+
+    client.shutdown().await;
+    Ok(())
+}
+
 async fn get_event(id: String, relays: Vec<String>) -> Result<(), Error> {
     if relays.is_empty() {
         return Err(Error::Message(
@@ -471,3 +727,61 @@ async fn get_event(id: String, relays: Vec<String>) -> Result<(), Error> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reply_tags_follow_the_thread_root() {
+        let relay_url = RelayUrl::parse("wss://relay.example.com").unwrap();
+        let (alice, bob, carol) = (Keys::generate(), Keys::generate(), Keys::generate());
+        let root = EventBuilder::text_note("root")
+            .sign_with_keys(&alice)
+            .unwrap();
+
+        let tags = reply_tags(&root, relay_url.clone(), bob.public_key());
+        assert_eq!(
+            tags[0].as_slice(),
+            [
+                "e",
+                &root.id.to_hex(),
+                "wss://relay.example.com",
+                "root",
+                &alice.public_key().to_hex()
+            ]
+        );
+        assert_eq!(tags.len(), 2);
+
+        // A reply using the older positional `e` tags, which carries carol and us as `p` tags
+        let reply = EventBuilder::text_note("reply")
+            .tags([
+                Tag::event(root.id),
+                Tag::public_key(alice.public_key()),
+                Tag::public_key(carol.public_key()),
+            ])
+            .sign_with_keys(&bob)
+            .unwrap();
+        let tags = reply_tags(&reply, relay_url, alice.public_key());
+        assert_eq!(tags[0].as_slice()[1], root.id.to_hex());
+        assert_eq!(tags[0].as_slice()[3], "root");
+        assert_eq!(tags[1].as_slice()[1], reply.id.to_hex());
+        assert_eq!(tags[1].as_slice()[3], "reply");
+        let public_keys: Vec<_> = Tags::from_list(tags).public_keys().copied().collect();
+        assert_eq!(public_keys, [bob.public_key(), carol.public_key()]);
+    }
+
+    #[test]
+    fn custom_emoji_reactions_need_a_url() {
+        let tag = emoji_tag(":kani:", Some("https://example.com/kani.png".to_string()))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            tag.as_slice(),
+            ["emoji", "kani", "https://example.com/kani.png"]
+        );
+        assert!(emoji_tag("+", None).unwrap().is_none());
+        assert!(emoji_tag(":kani:", None).is_err());
+        assert!(emoji_tag("🦀", Some("https://example.com/kani.png".to_string())).is_err());
+    }
+}
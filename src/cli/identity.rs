@@ -0,0 +1,203 @@
+use crate::cli::common::bunker_signer;
+use crate::config::{Identity, load_config, save_config};
+use crate::error::Error;
+use clap::{Parser, Subcommand};
+use colored::*;
+use comfy_table::{Cell, Table, presets::UTF8_FULL};
+use dialoguer::{Confirm, Password, theme::ColorfulTheme};
+use nostr::prelude::{FromBech32, ToBech32};
+use nostr::{Keys, SecretKey};
+use nostr_sdk::prelude::*;
+
+#[derive(Parser, Clone)]
+pub struct IdentityCommand {
+    #[command(subcommand)]
+    subcommand: IdentitySubcommand,
+}
+
+#[derive(Subcommand, Clone)]
+enum IdentitySubcommand {
+    /// List configured identities
+    List,
+    /// Add a new identity with a NIP-49 encrypted key
+    Add {
+        /// Name of the identity
+        name: String,
+        /// Generate a new key instead of entering an existing one
+        #[clap(long, default_value_t = false)]
+        generate: bool,
+        /// Relays for this identity
+        #[clap(long, short, action = clap::ArgAction::Append)]
+        relay: Vec<String>,
+        /// Stored wallet (see `nip47 wallet add`) used by default for this identity
+        #[clap(long)]
+        wallet: Option<String>,
+        /// Sign through this NIP-46 bunker (bunker://...) instead of a local key
+        #[clap(long, conflicts_with = "generate")]
+        bunker: Option<String>,
+    },
+    /// Set the default identity
+    Use {
+        /// Name of the identity
+        name: String,
+    },
+    /// Remove an identity
+    Remove {
+        /// Name of the identity
+        name: String,
+    },
+}
+
+pub async fn handle_identity_command(command: IdentityCommand) -> Result<(), Error> {
+    let mut config = load_config()?;
+
+    match command.subcommand {
+        IdentitySubcommand::List => {
+            if config.identities.is_empty() {
+                println!("No identities configured. Run `identity add <name>` to create one.");
+                return Ok(());
+            }
+            let mut table = Table::new();
+            table.load_preset(UTF8_FULL).set_header(vec![
+                "",
+                "Name",
+                "Public Key",
+                "Relays",
+                "Signer",
+                "Wallet",
+            ]);
+            for (name, identity) in &config.identities {
+                let is_default = config.default_identity.as_ref() == Some(name);
+                table.add_row(vec![
+                    Cell::new(if is_default { "*" } else { "" }),
+                    Cell::new(name),
+                    Cell::new(identity.public_key.clone().unwrap_or_default()),
+                    Cell::new(identity.relays.clone().unwrap_or_default().join("\n")),
+                    Cell::new(if identity.bunker_uri.is_some() {
+                        "bunker"
+                    } else {
+                        "key"
+                    }),
+                    Cell::new(match (&identity.wallet, &identity.nwc_uri) {
+                        (Some(wallet), _) => wallet.as_str(),
+                        (None, Some(_)) => "plaintext URI",
+                        (None, None) => "",
+                    }),
+                ]);
+            }
+            println!("{table}");
+        }
+        IdentitySubcommand::Add {
+            name,
+            generate,
+            relay,
+            wallet,
+            bunker,
+        } => {
+            if config.identities.contains_key(&name) {
+                return Err(Error::Message(format!("Identity '{name}' already exists")));
+            }
+            if let Some(wallet) = &wallet
+                && !config.wallets.contains_key(wallet)
+            {
+                return Err(Error::Message(format!(
+                    "Wallet '{wallet}' not found in config. Run `nip47 wallet add {wallet}` first."
+                )));
+            }
+
+            if let Some(bunker_uri) = bunker {
+                // Keep the app key so the bunker recognizes us on later runs
+                let app_key = Keys::generate().secret_key().to_bech32().unwrap();
+                let signer = bunker_signer(&bunker_uri, Some(&app_key))?;
+                let public_key = signer.get_public_key().await?;
+                let identity = Identity {
+                    public_key: Some(public_key.to_bech32().unwrap()),
+                    relays: (!relay.is_empty()).then_some(relay),
+                    wallet,
+                    bunker_uri: Some(bunker_uri),
+                    bunker_app_key: Some(app_key),
+                    ..Default::default()
+                };
+                config.identities.insert(name.clone(), identity);
+                if config.default_identity.is_none() {
+                    config.default_identity = Some(name.clone());
+                }
+                save_config(&config)?;
+                println!(
+                    "Identity '{}' added (bunker): {}",
+                    name,
+                    public_key.to_bech32().unwrap()
+                );
+                return Ok(());
+            }
+
+            let theme = ColorfulTheme::default();
+            let keys = if generate {
+                Keys::generate()
+            } else {
+                let secret_key: String = Password::with_theme(&theme)
+                    .with_prompt("Secret key (nsec)")
+                    .interact()?;
+                Keys::new(SecretKey::from_bech32(&secret_key)?)
+            };
+            let password = Password::with_theme(&theme)
+                .with_prompt("Enter a password to encrypt the secret key")
+                .with_confirmation("Confirm password", "Passwords do not match")
+                .interact()?;
+            let encrypted_key = keys.secret_key().encrypt(&password)?;
+
+            let identity = Identity {
+                public_key: Some(keys.public_key().to_bech32().unwrap()),
+                encrypted_secret_key: Some(encrypted_key.to_bech32()?),
+                relays: (!relay.is_empty()).then_some(relay),
+                wallet,
+                ..Default::default()
+            };
+            config.identities.insert(name.clone(), identity);
+            if config.default_identity.is_none() {
+                config.default_identity = Some(name.clone());
+            }
+            save_config(&config)?;
+            println!(
+                "Identity '{}' added: {}",
+                name,
+                keys.public_key().to_bech32().unwrap()
+            );
+        }
+        IdentitySubcommand::Use { name } => {
+            if !config.identities.contains_key(&name) {
+                return Err(Error::Message(format!(
+                    "Identity '{name}' not found in config"
+                )));
+            }
+            config.default_identity = Some(name.clone());
+            save_config(&config)?;
+            println!("Default identity set to '{name}'.");
+        }
+        IdentitySubcommand::Remove { name } => {
+            if !config.identities.contains_key(&name) {
+                return Err(Error::Message(format!(
+                    "Identity '{name}' not found in config"
+                )));
+            }
+            if !Confirm::with_theme(&ColorfulTheme::default())
+                .with_prompt(format!(
+                    "Remove identity '{name}'? Its encrypted key will be deleted from the config."
+                ))
+                .default(false)
+                .interact()?
+            {
+                println!("Removal cancelled.");
+                return Ok(());
+            }
+            config.identities.remove(&name);
+            if config.default_identity.as_ref() == Some(&name) {
+                config.default_identity = None;
+                println!("{}", "The default identity was removed.".yellow());
+            }
+            save_config(&config)?;
+            println!("Identity '{name}' removed.");
+        }
+    }
+    Ok(())
+}
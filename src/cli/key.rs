@@ -73,7 +73,7 @@ pub async fn handle_key_command(command: KeyCommand) -> Result<(), Error> {
                     .default(true)
                     .interact()?
                 {
-                    event::edit_profile(secret_key_bech32.clone(), relays.clone()).await?;
+                    event::edit_profile(keys.clone().into_nostr_signer(), relays.clone()).await?;
                 }
 
                 if Confirm::with_theme(&theme)
@@ -81,7 +81,7 @@ pub async fn handle_key_command(command: KeyCommand) -> Result<(), Error> {
                     .default(true)
                     .interact()?
                 {
-                    relay::edit_relays(secret_key_bech32.clone(), relays).await?;
+                    relay::edit_relays(keys.clone().into_nostr_signer(), relays).await?;
                 }
 
                 if Confirm::with_theme(&theme)
@@ -92,10 +92,19 @@ pub async fn handle_key_command(command: KeyCommand) -> Result<(), Error> {
                     let password = Password::with_theme(&theme)
                         .with_prompt("Enter a password to encrypt your secret key")
                         .interact()?;
-                    let sk = SecretKey::from_bech32(&secret_key_bech32)?;
-                    let encrypted_key = sk.encrypt(&password)?;
+                    let encrypted_key = keys.secret_key().encrypt(&password)?;
                     let mut config = load_config()?;
-                    config.encrypted_secret_key = Some(encrypted_key.to_bech32()?);
+                    match &command.common.identity {
+                        Some(name) => {
+                            let identity = config.identities.entry(name.clone()).or_default();
+                            identity.public_key = Some(keys.public_key().to_bech32().unwrap());
+                            identity.encrypted_secret_key = Some(encrypted_key.to_bech32()?);
+                            if config.default_identity.is_none() {
+                                config.default_identity = Some(name.clone());
+                            }
+                        }
+                        None => config.encrypted_secret_key = Some(encrypted_key.to_bech32()?),
+                    }
                     save_config(&config)?;
                     println!("Encrypted secret key saved to config file.");
                 }
@@ -1,34 +1,41 @@
-use crate::config::load_config;
+#[cfg(unix)]
+use crate::cli::agent::{print_exports, spawn_agent};
+use crate::cli::common::decrypt_configured_key;
 use crate::error::Error;
 use clap::Parser;
-use dialoguer::Password;
-use nostr::nips::nip49::EncryptedSecretKey;
-use nostr::prelude::{FromBech32, ToBech32};
 
-#[derive(Parser, Clone)]
-pub struct LoginCommand {}
-
-pub async fn handle_login_command(_command: LoginCommand) -> Result<(), Error> {
-    let config = load_config()?;
+/// Default idle timeout of the signing agent in seconds.
+pub const DEFAULT_IDLE_TIMEOUT: u64 = 3600;
 
-    let encrypted_key_bech32 = config.encrypted_secret_key.ok_or(Error::Message(
-        "No encrypted secret key found in config. Please run `key generate --wizard` or `key encrypt` first.".to_string(),
-    ))?;
+#[derive(Parser, Clone)]
+pub struct LoginCommand {
+    /// Named identity from the config file to log in as
+    #[clap(long)]
+    identity: Option<String>,
+    /// Seconds without requests after which the signing agent exits
+    #[clap(long, default_value_t = DEFAULT_IDLE_TIMEOUT)]
+    timeout: u64,
+}
 
-    let password = Password::new()
-        .with_prompt("Enter password to decrypt secret key")
-        .interact()?;
+pub async fn handle_login_command(command: LoginCommand) -> Result<(), Error> {
+    let secret_key = decrypt_configured_key(command.identity.as_ref())?;
 
-    let encrypted_key = EncryptedSecretKey::from_bech32(&encrypted_key_bech32)?;
-    let secret_key = encrypted_key.decrypt(&password)?;
+    // Only the agent socket path is exported; the key stays in the agent's memory
+    #[cfg(unix)]
+    {
+        let socket_path = spawn_agent(&secret_key, command.timeout)?;
+        print_exports(&socket_path);
+    }
 
-    println!(
-        "export NOSTR_SECRET_KEY={}",
-        secret_key.to_bech32().unwrap()
-    );
-    // stderr message to the user so it doesn't get captured by eval
-    eprintln!("Login successful. Key is now available in your shell environment.");
-    eprintln!("Run `eval $(kani-nostr-cli logout)` to clear the key.");
+    // The agent listens on a Unix socket, so elsewhere the key itself is exported as before
+    #[cfg(not(unix))]
+    {
+        let _ = command.timeout;
+        println!("export NOSTR_SECRET_KEY={secret_key}");
+        // stderr message to the user so it doesn't get captured by eval
+        eprintln!("Login successful. Key is now available in your shell environment.");
+        eprintln!("Run `eval $(kani-nostr-cli logout)` to clear the key.");
+    }
 
     Ok(())
 }
@@ -1,12 +1,26 @@
+#[cfg(unix)]
+use crate::agent::{AGENT_SOCKET_ENV, AgentRequest, request};
 use crate::error::Error;
 use clap::Parser;
+#[cfg(unix)]
+use std::path::PathBuf;
 
 #[derive(Parser, Clone)]
 pub struct LogoutCommand {}
 
 pub async fn handle_logout_command(_command: LogoutCommand) -> Result<(), Error> {
+    #[cfg(unix)]
+    {
+        if let Ok(socket_path) = std::env::var(AGENT_SOCKET_ENV)
+            && let Err(e) = request(&PathBuf::from(socket_path), &AgentRequest::Shutdown).await
+        {
+            eprintln!("{e}");
+        }
+        println!("unset {AGENT_SOCKET_ENV}");
+    }
+    // Also clear keys exported by older versions of `login` and by `login` outside Unix
     println!("unset NOSTR_SECRET_KEY");
     // stderr message to the user so it doesn't get captured by eval
-    eprintln!("Logout successful. Key has been cleared from your shell environment.");
+    eprintln!("Logout successful.");
     Ok(())
 }
@@ -1,9 +1,12 @@
 use clap::{Parser, Subcommand};
 
+#[cfg(unix)]
+pub mod agent;
 pub mod common;
 pub mod config;
 pub mod contact;
 pub mod event;
+pub mod identity;
 pub mod key;
 pub mod login;
 pub mod logout;
@@ -13,13 +16,21 @@ pub mod nip46;
 pub mod nip47;
 pub mod relay;
 pub mod uri;
+pub mod wallet;
+pub mod wallet_service;
+pub mod zap;
 
 use self::{
-    config::ConfigCommand, contact::ContactCommand, event::EventCommand, key::KeyCommand,
+    config::ConfigCommand, contact::ContactCommand, event::EventCommand,
+    identity::IdentityCommand, key::KeyCommand,
     login::LoginCommand, logout::LogoutCommand, nip05::Nip05Command, nip19::Nip19Command,
     nip46::Nip46Command, nip47::Nip47Command, relay::RelayCommand, uri::UriCommand,
+    zap::ZapCommand,
 };
 
+#[cfg(unix)]
+use self::agent::AgentCommand;
+
 #[derive(Parser, Clone)]
 pub struct CommonOptions {
     /// Secret key to use for signing events
@@ -29,6 +40,14 @@ pub struct CommonOptions {
     /// Relay to connect to
     #[clap(long, short, action = clap::ArgAction::Append)]
     pub relay: Vec<String>,
+
+    /// Named identity from the config file to use
+    #[clap(long)]
+    pub identity: Option<String>,
+
+    /// NIP-46 bunker URI (bunker://...) to sign events with
+    #[clap(long)]
+    pub bunker: Option<String>,
 }
 
 #[derive(Parser)]
@@ -40,10 +59,13 @@ pub struct Cli {
 
 #[derive(Subcommand, Clone)]
 enum Command {
-    /// Decrypts secret key and loads it into the shell environment
+    /// Decrypts secret key into a signing agent and exports its socket to the shell
     Login(LoginCommand),
-    /// Clears the decrypted secret key from the shell environment
+    /// Stops the signing agent and clears its socket from the shell environment
     Logout(LogoutCommand),
+    /// Local signing agent management
+    #[cfg(unix)]
+    Agent(AgentCommand),
     /// Keys management
     Key(KeyCommand),
     /// Event management
@@ -62,8 +84,12 @@ enum Command {
     Nip46(Nip46Command),
     /// NIP-47 Nostr Wallet Connect
     Nip47(Nip47Command),
+    /// NIP-57 Lightning zaps
+    Zap(ZapCommand),
     /// Config management
     Config(ConfigCommand),
+    /// Named identity management
+    Identity(IdentityCommand),
 }
 
 use crate::error::Error;
@@ -74,6 +100,8 @@ pub async fn run() -> Result<(), Error> {
     match cli.command {
         Command::Login(login_command) => login::handle_login_command(login_command).await?,
         Command::Logout(logout_command) => logout::handle_logout_command(logout_command).await?,
+        #[cfg(unix)]
+        Command::Agent(agent_command) => agent::handle_agent_command(agent_command).await?,
         Command::Key(key_command) => key::handle_key_command(key_command).await?,
         Command::Event(event_command) => event::handle_event_command(event_command).await?,
         Command::Contact(contact_command) => {
@@ -85,7 +113,11 @@ pub async fn run() -> Result<(), Error> {
         Command::Nip05(nip05_command) => nip05::handle_nip05_command(nip05_command).await?,
         Command::Nip46(nip46_command) => nip46::handle_nip46_command(nip46_command).await?,
         Command::Nip47(nip47_command) => nip47::handle_nip47_command(nip47_command).await?,
+        Command::Zap(zap_command) => zap::handle_zap_command(zap_command).await?,
         Command::Config(config_command) => config::handle_config_command(config_command).await?,
+        Command::Identity(identity_command) => {
+            identity::handle_identity_command(identity_command).await?
+        }
     }
 
     Ok(())
@@ -1,13 +1,27 @@
-use crate::cli::common::get_secret_key;
 use crate::cli::CommonOptions;
-use crate::config::load_config;
+use crate::cli::common::{
+    bunker_signer, connect_client, decrypt_message, get_identity, get_relays, get_secret_key,
+};
+use crate::config::{Config, load_config, save_config};
 use clap::Parser;
+use dialoguer::{Confirm, theme::ColorfulTheme};
 use nostr::UnsignedEvent;
-use nostr::nips::nip04;
 use nostr::nips::nip46::{
     NostrConnectMessage, NostrConnectMethod, NostrConnectRequest, NostrConnectURI,
 };
+use nostr::nips::nip44;
+use nostr_connect::signer::{
+    NostrConnectKeys, NostrConnectRemoteSigner, NostrConnectSignerActions,
+};
 use nostr_sdk::prelude::*;
+use qrcode::QrCode;
+use qrcode::render::unicode;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::time::{Duration, timeout};
 
 #[derive(Parser, Clone)]
@@ -17,203 +31,791 @@ pub struct Nip46Command {
     subcommand: Nip46Subcommand,
     #[command(flatten)]
     common: CommonOptions,
+    /// Seconds to wait for a response (default 30, or 300 for `pair`)
+    #[clap(long)]
+    timeout: Option<u64>,
 }
 
 #[derive(Parser, Clone)]
 pub enum Nip46Subcommand {
     /// Get public key from a remote signer
     GetPublicKey {
-        /// Bunker URI (nostrconnect://...)
+        /// Bunker URI (bunker://...)
         uri: String,
     },
     /// Sign an unsigned event using a remote signer
     SignEvent {
-        /// Bunker URI (nostrconnect://...)
+        /// Bunker URI (bunker://...)
         uri: String,
         /// Unsigned event to sign (JSON string)
         event_json: String,
     },
+    /// Send a `connect` request to a remote signer
+    Connect {
+        /// Bunker URI (bunker://...)
+        uri: String,
+        /// Connection secret (defaults to the one in the URI)
+        #[clap(long)]
+        secret: Option<String>,
+        /// Permissions to request (comma separated, e.g. "sign_event:1,nip44_encrypt")
+        #[clap(long)]
+        perms: Option<String>,
+    },
+    /// Check that a remote signer is reachable
+    Ping {
+        /// Bunker URI (bunker://...)
+        uri: String,
+    },
+    /// Encrypt a message with NIP-04 using a remote signer
+    Nip04Encrypt {
+        /// Bunker URI (bunker://...)
+        uri: String,
+        /// Public key of the other party (npub or hex)
+        public_key: String,
+        plaintext: String,
+    },
+    /// Decrypt a NIP-04 message using a remote signer
+    Nip04Decrypt {
+        /// Bunker URI (bunker://...)
+        uri: String,
+        /// Public key of the other party (npub or hex)
+        public_key: String,
+        ciphertext: String,
+    },
+    /// Encrypt a message with NIP-44 using a remote signer
+    Nip44Encrypt {
+        /// Bunker URI (bunker://...)
+        uri: String,
+        /// Public key of the other party (npub or hex)
+        public_key: String,
+        plaintext: String,
+    },
+    /// Decrypt a NIP-44 message using a remote signer
+    Nip44Decrypt {
+        /// Bunker URI (bunker://...)
+        uri: String,
+        /// Public key of the other party (npub or hex)
+        public_key: String,
+        ciphertext: String,
+    },
+    /// Ask a remote signer which relays it wants to use
+    SwitchRelays {
+        /// Bunker URI (bunker://...)
+        uri: String,
+    },
+    /// Run a remote signer (bunker) holding our key
+    Serve {
+        /// Secret clients must send with `connect` (random if omitted)
+        #[clap(long)]
+        secret: Option<String>,
+        /// Per-client permission policy file (TOML)
+        #[clap(long)]
+        policy: Option<PathBuf>,
+        /// Ask interactively for requests the policy does not allow
+        #[clap(long, requires = "policy")]
+        ask: bool,
+    },
+    /// Pair with a remote signer by showing a nostrconnect:// URI
+    Pair {
+        /// Application name shown by the signer
+        #[clap(long, default_value = "kani")]
+        name: String,
+        /// Permissions to request (comma separated, e.g. "sign_event:1,nip44_encrypt")
+        #[clap(long, default_value = DEFAULT_PERMS)]
+        perms: String,
+    },
 }
 
+/// Pairing waits for a human to scan the QR code, so it gets longer than a plain request.
+const PAIR_TIMEOUT_SECS: u64 = 300;
+
+const DEFAULT_PERMS: &str =
+    "get_public_key,sign_event,nip04_encrypt,nip04_decrypt,nip44_encrypt,nip44_decrypt";
+
 use crate::error::Error;
+use crate::rpc::{self, Match, RpcError};
 
 pub async fn handle_nip46_command(command: Nip46Command) -> Result<(), Error> {
-    let config = load_config()?;
-    let secret_key_str = get_secret_key(&command.common, &config)?;
+    let mut config = load_config()?;
+    let wait = Duration::from_secs(command.timeout.unwrap_or(rpc::DEFAULT_TIMEOUT_SECS));
 
     match command.subcommand {
         Nip46Subcommand::GetPublicKey { uri } => {
-            let sk = SecretKey::from_bech32(&secret_key_str)?;
-            let keys = Keys::new(sk);
-
+            let result = call_bunker(
+                &command.common,
+                &config,
+                &uri,
+                wait,
+                "get_public_key",
+                vec![],
+            )
+            .await?;
+            let public_key = PublicKey::parse(&result)?;
+            print_result("get_public_key", public_key.to_hex().into());
+        }
+        Nip46Subcommand::SignEvent { uri, event_json } => {
+            let unsigned_event = UnsignedEvent::from_json(&event_json)?;
+            let result = call_bunker(
+                &command.common,
+                &config,
+                &uri,
+                wait,
+                "sign_event",
+                vec![unsigned_event.as_json()],
+            )
+            .await?;
+            let event = Event::from_json(&result)?;
+            event.verify()?;
+            print_result("sign_event", serde_json::to_value(&event)?);
+        }
+        Nip46Subcommand::Connect { uri, secret, perms } => {
             let bunker_uri = NostrConnectURI::parse(&uri)?;
+            let remote_signer = bunker_uri
+                .remote_signer_public_key()
+                .ok_or_else(|| Error::Message("Not a bunker URI".to_string()))?;
+            let mut params = vec![remote_signer.to_hex()];
+            let secret = secret.or_else(|| bunker_uri.secret().map(str::to_string));
+            if secret.is_some() || perms.is_some() {
+                params.push(secret.unwrap_or_default());
+            }
+            params.extend(perms);
 
-            let bunker_pk = if let NostrConnectURI::Bunker {
-                remote_signer_public_key,
-                ..
-            } = &bunker_uri
-            {
-                *remote_signer_public_key
+            let result =
+                call_bunker(&command.common, &config, &uri, wait, "connect", params).await?;
+            print_result("connect", result.into());
+        }
+        Nip46Subcommand::Ping { uri } => {
+            let result = call_bunker(&command.common, &config, &uri, wait, "ping", vec![]).await?;
+            print_result("ping", result.into());
+        }
+        Nip46Subcommand::Nip04Encrypt {
+            uri,
+            public_key,
+            plaintext,
+        } => {
+            let params = vec![PublicKey::parse(&public_key)?.to_hex(), plaintext];
+            let result = call_bunker(
+                &command.common,
+                &config,
+                &uri,
+                wait,
+                "nip04_encrypt",
+                params,
+            )
+            .await?;
+            print_result("nip04_encrypt", result.into());
+        }
+        Nip46Subcommand::Nip04Decrypt {
+            uri,
+            public_key,
+            ciphertext,
+        } => {
+            let params = vec![PublicKey::parse(&public_key)?.to_hex(), ciphertext];
+            let result = call_bunker(
+                &command.common,
+                &config,
+                &uri,
+                wait,
+                "nip04_decrypt",
+                params,
+            )
+            .await?;
+            print_result("nip04_decrypt", result.into());
+        }
+        Nip46Subcommand::Nip44Encrypt {
+            uri,
+            public_key,
+            plaintext,
+        } => {
+            let params = vec![PublicKey::parse(&public_key)?.to_hex(), plaintext];
+            let result = call_bunker(
+                &command.common,
+                &config,
+                &uri,
+                wait,
+                "nip44_encrypt",
+                params,
+            )
+            .await?;
+            print_result("nip44_encrypt", result.into());
+        }
+        Nip46Subcommand::Nip44Decrypt {
+            uri,
+            public_key,
+            ciphertext,
+        } => {
+            let params = vec![PublicKey::parse(&public_key)?.to_hex(), ciphertext];
+            let result = call_bunker(
+                &command.common,
+                &config,
+                &uri,
+                wait,
+                "nip44_decrypt",
+                params,
+            )
+            .await?;
+            print_result("nip44_decrypt", result.into());
+        }
+        Nip46Subcommand::SwitchRelays { uri } => {
+            let result = call_bunker(
+                &command.common,
+                &config,
+                &uri,
+                wait,
+                "switch_relays",
+                vec![],
+            )
+            .await?;
+            // `null` or no result means the signer keeps its current relays
+            let relays: Option<Vec<String>> = if result.is_empty() {
+                None
             } else {
-                return Err(Error::Message("Not a bunker URI".to_string()));
+                serde_json::from_str(&result)?
             };
+            print_result("switch_relays", serde_json::to_value(relays)?);
+        }
+        Nip46Subcommand::Serve {
+            secret,
+            policy,
+            ask,
+        } => {
+            let keys = Keys::parse(&get_secret_key(&command.common, &config)?)?;
+            let relays = get_relays(&command.common, &config);
+            if relays.is_empty() {
+                return Err(Error::Message(
+                    "No relays provided in args or config".to_string(),
+                ));
+            }
+            let policy = policy.as_deref().map(load_policy).transpose()?;
+            let secret = secret.unwrap_or_else(random_secret);
 
-            let req = NostrConnectRequest::GetPublicKey;
-            let msg = NostrConnectMessage::request(&req);
-            let request_id = msg.id().to_string();
+            let signer = NostrConnectRemoteSigner::new(
+                NostrConnectKeys {
+                    signer: keys.clone(),
+                    user: keys,
+                },
+                relays,
+                Some(secret.clone()),
+                None,
+            )?;
 
-            let client = Client::new(keys.clone());
-            for relay in bunker_uri.relays() {
-                client.add_relay(relay.clone()).await?;
-            }
-            client.connect().await;
+            println!("{}", signer.bunker_uri());
+            eprintln!("Waiting for requests. Press Ctrl+C to stop.");
 
-            let builder = EventBuilder::nostr_connect(&keys, bunker_pk, msg)?;
-            let event = client.sign_event_builder(builder).await?;
-            client.send_event(&event).await?;
+            let approver = Approver::new(policy, ask, secret);
+            loop {
+                match signer.serve(&approver).await {
+                    // The signer stops at the first request it cannot parse; skip it and go on
+                    Err(nostr_connect::error::Error::Pool(
+                        nostr_sdk::pool::pool::Error::Handler(e),
+                    )) => {
+                        eprintln!("Ignored a malformed request: {e}");
+                    }
+                    result => break result?,
+                }
+            }
+        }
+        Nip46Subcommand::Pair { name, perms } => {
+            let wait = command.timeout.unwrap_or(PAIR_TIMEOUT_SECS);
+            let relays = get_relays(&command.common, &config);
+            let app_keys = Keys::generate();
+            let secret = random_secret();
 
-            println!(
-                "GetPublicKey request sent with id: {}",
-                event.id.to_bech32().unwrap()
-            );
+            let mut uri = Url::parse(&format!(
+                "nostrconnect://{}",
+                app_keys.public_key().to_hex()
+            ))?;
+            {
+                let mut query = uri.query_pairs_mut();
+                for relay in &relays {
+                    query.append_pair("relay", relay);
+                }
+                query.append_pair("secret", &secret);
+                query.append_pair("perms", &perms);
+                query.append_pair("name", &name);
+                // Older signers read the app name from the `metadata` parameter
+                query.append_pair("metadata", &serde_json::json!({ "name": name }).to_string());
+            }
 
-            // Handle response
+            let client = connect_client(app_keys.clone(), relays.clone()).await?;
             let filter = Filter::new()
-                .kind(Kind::EncryptedDirectMessage)
-                .author(bunker_pk)
-                .pubkey(keys.public_key())
+                .kind(Kind::NostrConnect)
+                .pubkey(app_keys.public_key())
                 .since(Timestamp::now());
-
             client.subscribe(filter, None).await?;
 
-            println!("Waiting for response...");
-
-            let mut notifications = client.notifications();
-
-            let fut = async {
-                while let Ok(notification) = notifications.recv().await {
-                    if let RelayPoolNotification::Event { event, .. } = notification {
-                        if event.kind == Kind::EncryptedDirectMessage {
-                            if let Ok(decrypted) =
-                                nip04::decrypt(keys.secret_key(), &event.pubkey, &event.content)
-                            {
-                                if let Ok(msg) = NostrConnectMessage::from_json(&decrypted) {
-                                    if msg.id() == request_id {
-                                        if let Ok(response) =
-                                            msg.to_response(NostrConnectMethod::GetPublicKey)
-                                        {
-                                            if let Some(result) = response.result {
-                                                if let Ok(pk) = result.to_get_public_key() {
-                                                    return Some(Ok(pk));
-                                                }
-                                            }
-                                            if let Some(error) = response.error {
-                                                return Some(Err(error));
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-                None
+            println!("{uri}\n");
+            let qr = QrCode::new(uri.as_str())
+                .map_err(|e| Error::Message(format!("Could not render QR code: {e}")))?;
+            println!(
+                "{}",
+                qr.render::<unicode::Dense1x2>()
+                    .dark_color(unicode::Dense1x2::Light)
+                    .light_color(unicode::Dense1x2::Dark)
+                    .build()
+            );
+            eprintln!(
+                "Scan the QR code or paste the URI into your signer. Waiting for it to connect..."
+            );
+
+            let signer_public_key = match timeout(
+                Duration::from_secs(wait),
+                wait_for_connect(&client, &app_keys, &secret),
+            )
+            .await
+            {
+                Ok(result) => result,
+                Err(_) => Err(RpcError::Timeout(wait).into()),
             };
+            client.shutdown().await;
+            let signer_public_key = signer_public_key?;
 
-            match timeout(Duration::from_secs(30), fut).await {
-                Ok(Some(Ok(pk))) => println!("Received public key: {}", pk.to_bech32().unwrap()),
-                Ok(Some(Err(e))) => println!("Error from bunker: {e}"),
-                _ => println!("Timeout or no response."),
+            // The secret was only for this pairing; from now on the signer knows our app key
+            let mut bunker_uri = Url::parse(&format!("bunker://{}", signer_public_key.to_hex()))?;
+            {
+                let mut query = bunker_uri.query_pairs_mut();
+                for relay in &relays {
+                    query.append_pair("relay", relay);
+                }
             }
+            let bunker_uri = bunker_uri.to_string();
+            let app_key = app_keys.secret_key().to_bech32().unwrap();
 
-            client.shutdown().await;
+            // Make sure the session works before saving it
+            let public_key = bunker_signer(&bunker_uri, Some(&app_key))?
+                .get_public_key()
+                .await?;
+
+            let identity =
+                get_identity(&command.common, &config)?.map(|(name, _)| name.to_string());
+            match &identity {
+                Some(name) => {
+                    let identity = config.identities.get_mut(name).expect("identity exists");
+                    identity.bunker_uri = Some(bunker_uri.clone());
+                    identity.bunker_app_key = Some(app_key.clone());
+                }
+                None => {
+                    config.bunker_uri = Some(bunker_uri.clone());
+                    config.bunker_app_key = Some(app_key.clone());
+                }
+            }
+            save_config(&config)?;
+
+            match identity {
+                Some(name) => println!(
+                    "Paired with {} and saved the session to identity '{name}'.",
+                    public_key.to_bech32().unwrap()
+                ),
+                None => println!(
+                    "Paired with {} and saved the session to the config.",
+                    public_key.to_bech32().unwrap()
+                ),
+            }
         }
-        Nip46Subcommand::SignEvent { uri, event_json } => {
-            let sk = SecretKey::from_bech32(&secret_key_str)?;
-            let keys = Keys::new(sk);
+    }
+    Ok(())
+}
 
-            let bunker_uri = NostrConnectURI::parse(&uri)?;
+/// Send one request to the remote signer of `uri`, see [`client_keys`] for the client key.
+async fn call_bunker(
+    common: &CommonOptions,
+    config: &Config,
+    uri: &str,
+    wait: Duration,
+    method: &str,
+    params: Vec<String>,
+) -> Result<String, Error> {
+    let keys = client_keys(common, config, uri)?;
+    let (client, bunker_pk) = connect_bunker(&keys, uri).await?;
+    let result = send_nip46_request(&client, &keys, bunker_pk, wait, method, params).await;
+    client.shutdown().await;
+    result
+}
 
-            let bunker_pk = if let NostrConnectURI::Bunker {
-                remote_signer_public_key,
-                ..
-            } = &bunker_uri
-            {
-                *remote_signer_public_key
-            } else {
-                return Err(Error::Message("Not a bunker URI".to_string()));
+/// Client key for talking to the bunker of `uri`: `--secret-key` if given, else the app key
+/// saved by `pair` for that bunker, else a throwaway key. The user's own key is never sent.
+fn client_keys(common: &CommonOptions, config: &Config, uri: &str) -> Result<Keys, Error> {
+    if let Some(secret_key) = &common.secret_key {
+        return Ok(Keys::parse(secret_key)?);
+    }
+    let (bunker_uri, app_key) = match get_identity(common, config)? {
+        Some((_, identity)) => (&identity.bunker_uri, &identity.bunker_app_key),
+        None => (&config.bunker_uri, &config.bunker_app_key),
+    };
+    let remote_signer = NostrConnectURI::parse(uri)?
+        .remote_signer_public_key()
+        .copied();
+    if let (Some(bunker_uri), Some(app_key)) = (bunker_uri, app_key)
+        && remote_signer.is_some()
+        && NostrConnectURI::parse(bunker_uri)?
+            .remote_signer_public_key()
+            .copied()
+            == remote_signer
+    {
+        return Ok(Keys::parse(app_key)?);
+    }
+    Ok(Keys::generate())
+}
+
+/// Connect to the relays of a `bunker://` URI and return the client with the remote signer key.
+async fn connect_bunker(keys: &Keys, uri: &str) -> Result<(Client, PublicKey), Error> {
+    let bunker_uri = NostrConnectURI::parse(uri)?;
+    let NostrConnectURI::Bunker {
+        remote_signer_public_key,
+        ..
+    } = &bunker_uri
+    else {
+        return Err(Error::Message("Not a bunker URI".to_string()));
+    };
+
+    let client = Client::new(keys.clone());
+    for relay in bunker_uri.relays() {
+        client.add_relay(relay.clone()).await?;
+    }
+    client.connect().await;
+    Ok((client, *remote_signer_public_key))
+}
+
+/// A NIP-46 response. Kept untyped so methods unknown to `nostr` (e.g. `switch_relays`) work too.
+#[derive(Deserialize)]
+struct Nip46Response {
+    id: String,
+    #[serde(default)]
+    result: Option<String>,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+/// Send a `method` request to the remote signer as a kind-24133 event and wait for its result.
+async fn send_nip46_request(
+    client: &Client,
+    keys: &Keys,
+    remote_signer: PublicKey,
+    wait: Duration,
+    method: &str,
+    params: Vec<String>,
+) -> Result<String, Error> {
+    let request_id = random_secret();
+    let msg = serde_json::json!({ "id": request_id, "method": method, "params": params });
+    let content = nip44::encrypt(
+        keys.secret_key(),
+        &remote_signer,
+        msg.to_string(),
+        nip44::Version::default(),
+    )?;
+    let event = EventBuilder::new(Kind::NostrConnect, content)
+        .tag(Tag::public_key(remote_signer))
+        .sign_with_keys(keys)?;
+
+    eprintln!(
+        "Sending {method} request with id: {}",
+        event.id.to_bech32().unwrap()
+    );
+    eprintln!("Waiting for response...");
+
+    rpc::call(
+        client,
+        &event,
+        Kind::NostrConnect,
+        remote_signer,
+        Match::Payload,
+        wait,
+        |event| {
+            let decrypted = decrypt_message(keys.secret_key(), &event.pubkey, &event.content)
+                .map_err(|e| RpcError::Decrypt(e.to_string()))?;
+            let Ok(response) = serde_json::from_str::<Nip46Response>(&decrypted) else {
+                return Ok(None);
             };
+            if response.id != request_id {
+                return Ok(None);
+            }
+            let error = response.error.filter(|e| !e.is_empty());
+            if response.result.as_deref() == Some("auth_url") {
+                // The signer wants the user to approve in a browser; its answer follows later
+                if let Some(url) = error {
+                    eprintln!("The remote signer requires authorization. Open this URL:\n{url}");
+                }
+                return Ok(None);
+            }
+            match error {
+                Some(error) => Err(RpcError::Remote(error).into()),
+                None => Ok(Some(response.result.unwrap_or_default())),
+            }
+        },
+    )
+    .await
+}
 
-            let unsigned_event = UnsignedEvent::from_json(&event_json)?;
-            let req = NostrConnectRequest::SignEvent(unsigned_event);
-            let msg = NostrConnectMessage::request(&req);
-            let request_id = msg.id().to_string();
+fn print_result(method: &str, result: serde_json::Value) {
+    let output = serde_json::json!({ "method": method, "result": result });
+    println!("{}", serde_json::to_string_pretty(&output).unwrap());
+}
+
+fn random_secret() -> String {
+    SecretKey::generate().to_secret_hex()[..32].to_string()
+}
 
-            let client = Client::new(keys.clone());
-            for relay in bunker_uri.relays() {
-                client.add_relay(relay.clone()).await?;
+/// Wait for the signer's `connect` answer carrying our secret and return the signer's public key.
+async fn wait_for_connect(
+    client: &Client,
+    app_keys: &Keys,
+    secret: &str,
+) -> Result<PublicKey, Error> {
+    let mut notifications = client.notifications();
+    loop {
+        let notification = match notifications.recv().await {
+            Ok(notification) => notification,
+            // The signer may still answer after the missed messages, keep waiting
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => {
+                return Err(Error::Message(
+                    "Relay connection closed while waiting for the signer".to_string(),
+                ));
+            }
+        };
+        let RelayPoolNotification::Event { event, .. } = notification else {
+            continue;
+        };
+        if event.kind != Kind::NostrConnect {
+            continue;
+        }
+        let Ok(decrypted) = decrypt_message(app_keys.secret_key(), &event.pubkey, &event.content)
+        else {
+            continue;
+        };
+        match NostrConnectMessage::from_json(&decrypted) {
+            // Current NIP-46: the signer answers with the secret as the result
+            Ok(NostrConnectMessage::Response {
+                result: Some(result),
+                ..
+            }) if result == secret => return Ok(event.pubkey),
+            Ok(NostrConnectMessage::Response {
+                error: Some(error), ..
+            }) => {
+                return Err(Error::Message(format!(
+                    "Signer refused the connection: {error}"
+                )));
+            }
+            // Older signers send a `connect` request instead
+            Ok(msg @ NostrConnectMessage::Request { .. }) => {
+                if let Ok(NostrConnectRequest::Connect {
+                    secret: Some(s), ..
+                }) = msg.to_request()
+                    && s == secret
+                {
+                    return Ok(event.pubkey);
+                }
             }
-            client.connect().await;
+            _ => {}
+        }
+        eprintln!(
+            "Ignoring a message from {} without the expected secret",
+            event.pubkey.to_bech32().unwrap()
+        );
+    }
+}
 
-            let builder = EventBuilder::nostr_connect(&keys, bunker_pk, msg)?;
-            let event = client.sign_event_builder(builder).await?;
-            client.send_event(&event).await?;
+/// What a single client is allowed to do without asking.
+#[derive(Deserialize, Debug, Clone, Default)]
+struct ClientPolicy {
+    #[serde(default)]
+    methods: Vec<NostrConnectMethod>,
+    /// Event kinds `sign_event` may sign. All kinds if omitted.
+    kinds: Option<Vec<u16>>,
+}
 
-            println!(
-                "SignEvent request sent with id: {}",
-                event.id.to_bech32().unwrap()
-            );
+impl ClientPolicy {
+    fn allows(&self, req: &NostrConnectRequest) -> bool {
+        if !self.methods.contains(&req.method()) {
+            return false;
+        }
+        match (req, &self.kinds) {
+            (NostrConnectRequest::SignEvent(unsigned), Some(kinds)) => {
+                kinds.contains(&unsigned.kind.as_u16())
+            }
+            _ => true,
+        }
+    }
+}
 
-            let filter = Filter::new()
-                .kind(Kind::EncryptedDirectMessage)
-                .author(bunker_pk)
-                .pubkey(keys.public_key())
-                .since(Timestamp::now());
+#[derive(Deserialize)]
+struct PolicyFile {
+    #[serde(default)]
+    clients: BTreeMap<String, ClientPolicy>,
+}
 
-            client.subscribe(filter, None).await?;
+/// Load a policy file whose `[clients.<npub|hex>]` tables list the allowed methods.
+fn load_policy(path: &Path) -> Result<HashMap<PublicKey, ClientPolicy>, Error> {
+    let file: PolicyFile = toml::from_str(&fs::read_to_string(path)?)?;
+    file.clients
+        .into_iter()
+        .map(|(key, policy)| {
+            let public_key = PublicKey::parse(&key).map_err(|e| {
+                Error::Message(format!("Invalid client public key '{key}' in policy: {e}"))
+            })?;
+            Ok((public_key, policy))
+        })
+        .collect()
+}
 
-            println!("Waiting for response...");
-
-            let mut notifications = client.notifications();
-
-            let fut = async {
-                while let Ok(notification) = notifications.recv().await {
-                    if let RelayPoolNotification::Event { event, .. } = notification {
-                        if event.kind == Kind::EncryptedDirectMessage {
-                            if let Ok(decrypted) =
-                                nip04::decrypt(keys.secret_key(), &event.pubkey, &event.content)
-                            {
-                                if let Ok(msg) = NostrConnectMessage::from_json(&decrypted) {
-                                    if msg.id() == request_id {
-                                        if let Ok(response) =
-                                            msg.to_response(NostrConnectMethod::SignEvent)
-                                        {
-                                            if let Some(result) = response.result {
-                                                if let Ok(signed_event) = result.to_sign_event() {
-                                                    return Some(Ok(signed_event));
-                                                }
-                                            }
-                                            if let Some(error) = response.error {
-                                                return Some(Err(error));
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-                None
-            };
+struct Approver {
+    policy: Option<HashMap<PublicKey, ClientPolicy>>,
+    ask: bool,
+    /// Secret a client has to send with `connect`
+    secret: String,
+    /// Clients that sent `connect` with the secret
+    connected: Mutex<HashSet<PublicKey>>,
+}
 
-            match timeout(Duration::from_secs(30), fut).await {
-                Ok(Some(Ok(evt))) => println!("Received signed event: {}", evt.as_json()),
-                Ok(Some(Err(e))) => println!("Error from bunker: {e}"),
-                _ => println!("Timeout or no response."),
+impl Approver {
+    fn new(policy: Option<HashMap<PublicKey, ClientPolicy>>, ask: bool, secret: String) -> Self {
+        Self {
+            policy,
+            ask,
+            secret,
+            connected: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Whether `public_key` may send requests other than `connect`: it connected with the
+    /// secret or is listed in the policy.
+    fn is_known(&self, public_key: &PublicKey) -> bool {
+        self.connected.lock().unwrap().contains(public_key)
+            || self
+                .policy
+                .as_ref()
+                .is_some_and(|policy| policy.contains_key(public_key))
+    }
+}
+
+impl NostrConnectSignerActions for Approver {
+    fn approve(&self, public_key: &PublicKey, req: &NostrConnectRequest) -> bool {
+        let is_connect = matches!(req, NostrConnectRequest::Connect { .. });
+        let approved = match req {
+            NostrConnectRequest::Ping => true,
+            // Only the secret from the bunker URI lets a client in
+            NostrConnectRequest::Connect { secret, .. }
+                if secret.as_ref() != Some(&self.secret) =>
+            {
+                false
             }
+            _ if !is_connect && !self.is_known(public_key) => false,
+            _ => {
+                let allowed = self
+                    .policy
+                    .as_ref()
+                    .and_then(|policy| policy.get(public_key))
+                    .is_some_and(|p| p.allows(req));
+                allowed || ((self.policy.is_none() || self.ask) && confirm_request(public_key, req))
+            }
+        };
+        if approved && is_connect {
+            self.connected.lock().unwrap().insert(*public_key);
+        }
 
-            client.shutdown().await;
+        eprintln!(
+            "{} {} from {}",
+            if approved { "Approved" } else { "Rejected" },
+            req.method(),
+            public_key.to_bech32().unwrap()
+        );
+        approved
+    }
+}
+
+/// Lets `serve` be restarted with the same approver, keeping the connected clients.
+impl NostrConnectSignerActions for &Approver {
+    fn approve(&self, public_key: &PublicKey, req: &NostrConnectRequest) -> bool {
+        Approver::approve(self, public_key, req)
+    }
+}
+
+fn confirm_request(public_key: &PublicKey, req: &NostrConnectRequest) -> bool {
+    let detail = match req {
+        NostrConnectRequest::SignEvent(unsigned) => {
+            let content: String = unsigned.content.chars().take(80).collect();
+            format!(" (kind {}: {content:?})", unsigned.kind.as_u16())
+        }
+        NostrConnectRequest::Nip04Encrypt { public_key, .. }
+        | NostrConnectRequest::Nip04Decrypt { public_key, .. }
+        | NostrConnectRequest::Nip44Encrypt { public_key, .. }
+        | NostrConnectRequest::Nip44Decrypt { public_key, .. } => {
+            format!(" (with {})", public_key.to_bech32().unwrap())
+        }
+        _ => String::new(),
+    };
+    Confirm::with_theme(&ColorfulTheme::default())
+        .with_prompt(format!(
+            "{} requests {}{detail}. Approve?",
+            public_key.to_bech32().unwrap(),
+            req.method()
+        ))
+        .default(false)
+        .interact()
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr::nips::nip04;
+
+    #[test]
+    fn rejects_clients_that_did_not_connect() {
+        let client = Keys::generate().public_key();
+        let stranger = Keys::generate().public_key();
+        let policy: ClientPolicy =
+            toml::from_str("methods = [\"connect\", \"get_public_key\"]").unwrap();
+        let approver = Approver::new(
+            Some(HashMap::from([(client, policy)])),
+            false,
+            "s3cret".to_string(),
+        );
+        let connect = |secret: &str| NostrConnectRequest::Connect {
+            public_key: client,
+            secret: Some(secret.to_string()),
+        };
+
+        assert!(!approver.approve(&stranger, &NostrConnectRequest::GetPublicKey));
+        assert!(!approver.approve(&client, &connect("wrong")));
+        assert!(approver.approve(&client, &connect("s3cret")));
+        assert!(approver.connected.lock().unwrap().contains(&client));
+        assert!(approver.approve(&client, &NostrConnectRequest::GetPublicKey));
+    }
+
+    #[test]
+    fn policy_restricts_methods_and_kinds() {
+        let policy: ClientPolicy =
+            toml::from_str("methods = [\"get_public_key\", \"sign_event\"]\nkinds = [1]").unwrap();
+        let keys = Keys::generate();
+        let note = EventBuilder::text_note("hi").build(keys.public_key());
+        let reaction = EventBuilder::new(Kind::Reaction, "+").build(keys.public_key());
+
+        assert!(policy.allows(&NostrConnectRequest::GetPublicKey));
+        assert!(policy.allows(&NostrConnectRequest::SignEvent(note)));
+        assert!(!policy.allows(&NostrConnectRequest::SignEvent(reaction)));
+        assert!(!policy.allows(&NostrConnectRequest::Nip44Decrypt {
+            public_key: keys.public_key(),
+            ciphertext: String::new(),
+        }));
+    }
+
+    #[test]
+    fn decrypts_nip04_and_nip44_payloads() {
+        let alice = Keys::generate();
+        let bob = Keys::generate();
+        let v1 = nip04::encrypt(alice.secret_key(), &bob.public_key(), "hello").unwrap();
+        let v2 = nip44::encrypt(
+            alice.secret_key(),
+            &bob.public_key(),
+            "hello",
+            nip44::Version::default(),
+        )
+        .unwrap();
+
+        for payload in [v1, v2] {
+            let plaintext = decrypt_message(bob.secret_key(), &alice.public_key(), &payload);
+            assert_eq!(plaintext.unwrap(), "hello");
         }
     }
-    Ok(())
 }
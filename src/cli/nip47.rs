@@ -1,218 +1,902 @@
-use clap::Parser;
-use nostr::nips::nip47::{NostrWalletConnectURI, PayInvoiceRequest, Request, Response};
+use crate::bolt11;
+use crate::cli::CommonOptions;
+use crate::cli::common::{get_identity, sats_to_msats};
+use crate::cli::wallet::{
+    WalletCommand, check_limits, handle_wallet_command, reserve_payment, settle_payment, wallet_uri,
+};
+use crate::cli::wallet_service::{ServeCommand, handle_serve_command};
+use crate::config::load_config;
+use clap::{ArgGroup, Parser, ValueEnum};
+use colored::*;
+use comfy_table::{Cell, Table, presets::UTF8_FULL};
+use dialoguer::{Confirm, theme::ColorfulTheme};
+use nostr::nips::nip47::{
+    KeysendTLVRecord, ListTransactionsRequest, LookupInvoiceRequest, LookupInvoiceResponse,
+    MakeInvoiceRequest, MultiPayInvoiceRequest, NostrWalletConnectURI, Notification,
+    PayInvoiceRequest, PayKeysendRequest, PaymentNotification, Request, Response, ResponseResult,
+    TransactionType,
+};
 use nostr_sdk::prelude::*;
-use tokio::time::{Duration, timeout};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::Duration;
 
 #[derive(Parser, Clone)]
 pub struct Nip47Command {
     /// Nostr Wallet Connect (NIP-47)
     #[command(subcommand)]
     subcommand: Nip47Subcommand,
+    #[command(flatten)]
+    common: CommonOptions,
+    /// Stored wallet to use instead of a Wallet Connect URI
+    #[clap(long)]
+    wallet: Option<String>,
+    /// Seconds to wait for the wallet's response
+    #[clap(long, default_value_t = rpc::DEFAULT_TIMEOUT_SECS)]
+    timeout: u64,
 }
 
 #[derive(Parser, Clone)]
 pub enum Nip47Subcommand {
+    /// Manage stored wallet connections
+    Wallet(WalletCommand),
     /// Get info from a wallet
     GetInfo {
-        /// Wallet Connect URI (nostr+walletconnect://...)
-        uri: String,
+        /// Wallet Connect URI (nostr+walletconnect://...), defaults to the stored wallet
+        uri: Option<String>,
     },
     /// Get balance from a wallet
     GetBalance {
-        /// Wallet Connect URI (nostr+walletconnect://...)
-        uri: String,
+        /// Wallet Connect URI (nostr+walletconnect://...), defaults to the stored wallet
+        uri: Option<String>,
     },
     /// Pay an invoice
     PayInvoice {
-        /// Wallet Connect URI (nostr+walletconnect://...)
+        /// Wallet Connect URI (nostr+walletconnect://...), or the invoice when using a stored wallet
+        #[clap(value_name = "URI")]
         uri: String,
         /// Bolt11 invoice
-        invoice: String,
+        invoice: Option<String>,
+        /// Amount in sats, for invoices that do not set one
+        #[clap(long)]
+        amount: Option<u64>,
+        /// Pay without asking for confirmation
+        #[clap(short, long)]
+        yes: bool,
+    },
+    /// Send a keysend payment to a node
+    PayKeysend {
+        /// Wallet Connect URI (nostr+walletconnect://...), defaults to the stored wallet
+        uri: Option<String>,
+        /// Public key of the receiving node (hex)
+        #[clap(long)]
+        pubkey: String,
+        /// Amount in sats
+        #[clap(long)]
+        amount: u64,
+        /// Custom TLV record as <type>=<hex value> (repeatable)
+        #[clap(long, action = clap::ArgAction::Append)]
+        tlv: Vec<String>,
+    },
+    /// Pay a batch of invoices listed in a file
+    MultiPay {
+        /// JSON array of {"invoice", "amount", "id"} or one "<invoice> [amount]" per line
+        file: String,
+        /// Wallet Connect URI (nostr+walletconnect://...), defaults to the stored wallet
+        uri: Option<String>,
+    },
+    /// Create an invoice
+    MakeInvoice {
+        /// Wallet Connect URI (nostr+walletconnect://...), defaults to the stored wallet
+        uri: Option<String>,
+        /// Amount in sats
+        #[clap(long)]
+        amount: u64,
+        /// Invoice description
+        #[clap(long)]
+        description: Option<String>,
+        /// Seconds until the invoice expires
+        #[clap(long)]
+        expiry: Option<u64>,
+    },
+    /// Look up an invoice by payment hash or bolt11 string
+    #[clap(group(ArgGroup::new("target").required(true).args(["payment_hash", "invoice"])))]
+    LookupInvoice {
+        /// Wallet Connect URI (nostr+walletconnect://...), defaults to the stored wallet
+        uri: Option<String>,
+        /// Payment hash of the invoice
+        #[clap(long)]
+        payment_hash: Option<String>,
+        /// Bolt11 invoice
+        #[clap(long)]
+        invoice: Option<String>,
+    },
+    /// List wallet transactions
+    ListTransactions {
+        /// Wallet Connect URI (nostr+walletconnect://...), defaults to the stored wallet
+        uri: Option<String>,
+        /// Only transactions created at or after this unix timestamp
+        #[clap(long)]
+        from: Option<u64>,
+        /// Only transactions created at or before this unix timestamp
+        #[clap(long)]
+        until: Option<u64>,
+        /// Maximum number of transactions
+        #[clap(long)]
+        limit: Option<u64>,
+        /// Only incoming or outgoing transactions
+        #[clap(long = "type", value_enum)]
+        transaction_type: Option<TransactionKind>,
+        /// Output as JSON
+        #[clap(long)]
+        json: bool,
+    },
+    /// Print payment notifications from the wallet as they arrive
+    Listen {
+        /// Wallet Connect URI (nostr+walletconnect://...), defaults to the stored wallet
+        uri: Option<String>,
+        /// Print each notification as a line of JSON
+        #[clap(long)]
+        json: bool,
+        /// Shell command run for each notification, with its JSON on stdin
+        #[clap(long, value_name = "COMMAND")]
+        exec: Option<String>,
     },
+    /// Run a wallet service backed by a local ledger, for testing
+    Serve(ServeCommand),
+}
+
+/// NIP-44 encrypted wallet notification; kind 23196 is the NIP-04 variant.
+pub(crate) const WALLET_CONNECT_NOTIFICATION_NIP44: Kind = Kind::Custom(23197);
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Debug)]
+pub enum TransactionKind {
+    Incoming,
+    Outgoing,
+}
+
+impl From<TransactionKind> for TransactionType {
+    fn from(kind: TransactionKind) -> Self {
+        match kind {
+            TransactionKind::Incoming => TransactionType::Incoming,
+            TransactionKind::Outgoing => TransactionType::Outgoing,
+        }
+    }
 }
 
 use crate::error::Error;
+use crate::rpc::{self, Match, RpcError};
+
+/// Use the given Wallet Connect URI, else `--wallet`, the identity's wallet or the default wallet.
+fn resolve_nwc_uri(
+    uri: Option<String>,
+    wallet: Option<&String>,
+    common: &CommonOptions,
+) -> Result<NostrWalletConnectURI, Error> {
+    Ok(resolve_wallet(uri, wallet, common)?.0)
+}
+
+/// Like [`resolve_nwc_uri`], also returning the name of the stored wallet if one was picked.
+pub fn resolve_wallet(
+    uri: Option<String>,
+    wallet: Option<&String>,
+    common: &CommonOptions,
+) -> Result<(NostrWalletConnectURI, Option<String>), Error> {
+    if let Some(uri) = uri {
+        return Ok((NostrWalletConnectURI::parse(&uri)?, None));
+    }
+    let config = load_config()?;
+    if let Some(name) = wallet {
+        return Ok((wallet_uri(name, &config)?, Some(name.clone())));
+    }
+    if let Some((identity_name, identity)) = get_identity(common, &config)? {
+        if let Some(name) = &identity.wallet {
+            return Ok((wallet_uri(name, &config)?, Some(name.clone())));
+        }
+        if let Some(uri) = &identity.nwc_uri {
+            eprintln!(
+                "{}",
+                format!(
+                    "WARNING: Identity '{identity_name}' keeps its Wallet Connect URI in plaintext.\n\
+                     Run `nip47 wallet add <name> --from-identity {identity_name}` to encrypt it."
+                )
+                .yellow()
+            );
+            return Ok((NostrWalletConnectURI::parse(uri)?, None));
+        }
+    }
+    match &config.default_wallet {
+        Some(name) => Ok((wallet_uri(name, &config)?, Some(name.clone()))),
+        None => Err(Error::Message(
+            "No Wallet Connect URI given and no default wallet. Run `nip47 wallet add <name>` first."
+                .to_string(),
+        )),
+    }
+}
 
 pub async fn handle_nip47_command(command: Nip47Command) -> Result<(), Error> {
+    let wait = Duration::from_secs(command.timeout);
+
     match command.subcommand {
+        Nip47Subcommand::Wallet(wallet_command) => handle_wallet_command(wallet_command).await?,
         Nip47Subcommand::GetInfo { uri } => {
-            let nwc_uri = NostrWalletConnectURI::parse(&uri)?;
-
-            let request = Request::get_info();
-            let event = request.to_event(&nwc_uri)?;
-
-            let keys = Keys::new(nwc_uri.secret.clone());
-            let client = Client::new(keys);
+            let nwc_uri = resolve_nwc_uri(uri, command.wallet.as_ref(), &command.common)?;
+            let info = nwc_request(&nwc_uri, Request::get_info(), wait)
+                .await?
+                .to_get_info()?;
+            println!("Received info: {info:#?}");
+        }
+        Nip47Subcommand::GetBalance { uri } => {
+            let nwc_uri = resolve_nwc_uri(uri, command.wallet.as_ref(), &command.common)?;
+            let balance = nwc_request(&nwc_uri, Request::get_balance(), wait)
+                .await?
+                .to_get_balance()?;
+            println!("Received balance: {} sats", balance.balance / 1000);
+        }
+        Nip47Subcommand::PayInvoice {
+            uri,
+            invoice,
+            amount,
+            yes,
+        } => {
+            let (uri, invoice) = match invoice {
+                Some(invoice) => (Some(uri), invoice),
+                None => (None, uri),
+            };
 
-            for relay in nwc_uri.relays.iter() {
-                client.add_relay(relay.clone()).await?;
+            // Everything is checked locally before the wallet service sees the invoice
+            let decoded = bolt11::decode(&invoice)?;
+            let msats = match (decoded.amount_msats, amount) {
+                (Some(msats), None) => msats,
+                (None, Some(sats)) => sats_to_msats(sats)?,
+                (Some(_), Some(_)) => {
+                    return Err(Error::Message(
+                        "The invoice already sets an amount, drop --amount".to_string(),
+                    ));
+                }
+                (None, None) => {
+                    return Err(Error::Message(
+                        "The invoice has no amount, pass --amount <sats>".to_string(),
+                    ));
+                }
+            };
+            print_invoice(&decoded, msats);
+            if decoded.expires_at() <= Timestamp::now().as_u64() {
+                return Err(Error::Message("The invoice has expired".to_string()));
             }
-            client.connect().await;
 
-            let event_id = client.send_event(&event).await?;
-            println!(
-                "GetInfo request sent with id: {}",
-                event_id.to_bech32().unwrap()
-            );
+            let (nwc_uri, wallet_name) =
+                resolve_wallet(uri, command.wallet.as_ref(), &command.common)?;
+            let config = load_config()?;
+            if let Some(name) = &wallet_name {
+                check_limits(name, &config, msats)?;
+            }
+            if !yes
+                && !Confirm::with_theme(&ColorfulTheme::default())
+                    .with_prompt(format!("Pay {} sats?", msats.div_ceil(1000)))
+                    .default(false)
+                    .interact()?
+            {
+                println!("Payment cancelled.");
+                return Ok(());
+            }
 
-            // Handle response
-            let filter = Filter::new()
-                .kind(Kind::WalletConnectResponse)
-                .author(nwc_uri.public_key)
-                .event(*event_id)
-                .since(Timestamp::now());
-
-            client.subscribe(filter, None).await?;
-
-            println!("Waiting for response...");
-
-            let mut notifications = client.notifications();
-
-            let fut = async {
-                while let Ok(notification) = notifications.recv().await {
-                    if let RelayPoolNotification::Event { event, .. } = notification {
-                        if event.kind == Kind::WalletConnectResponse {
-                            if let Ok(response) = Response::from_event(&nwc_uri, &event) {
-                                match response.to_get_info() {
-                                    Ok(info) => return Some(Ok(info)),
-                                    Err(e) => return Some(Err(Error::Message(e.to_string()))),
-                                }
-                            }
-                        }
+            let params = PayInvoiceRequest {
+                id: None,
+                invoice,
+                amount: amount.map(|_| msats),
+            };
+            if let Some(name) = &wallet_name {
+                reserve_payment(name, &config, &[msats])?;
+            }
+            let res = nwc_request(&nwc_uri, Request::pay_invoice(params), wait)
+                .await
+                .and_then(|response| Ok(response.to_pay_invoice()?));
+            if let Some(name) = &wallet_name {
+                settle_payment(
+                    name,
+                    msats,
+                    res.as_ref().map(|res| res.fees_paid.unwrap_or(0)),
+                )?;
+            }
+            println!("Invoice paid! Preimage: {}", res?.preimage);
+        }
+        Nip47Subcommand::PayKeysend {
+            uri,
+            pubkey,
+            amount,
+            tlv,
+        } => {
+            let (nwc_uri, wallet_name) =
+                resolve_wallet(uri, command.wallet.as_ref(), &command.common)?;
+            let msats = sats_to_msats(amount)?;
+
+            let params = PayKeysendRequest {
+                id: None,
+                amount: msats,
+                pubkey,
+                preimage: None,
+                tlv_records: tlv
+                    .iter()
+                    .map(|record| parse_tlv(record))
+                    .collect::<Result<_, _>>()?,
+            };
+            if let Some(name) = &wallet_name {
+                reserve_payment(name, &load_config()?, &[msats])?;
+            }
+            let res = nwc_request(&nwc_uri, Request::pay_keysend(params), wait)
+                .await
+                .and_then(|response| Ok(response.to_pay_keysend()?));
+            if let Some(name) = &wallet_name {
+                // Keysend responses don't report fees
+                settle_payment(name, msats, res.as_ref().map(|_| 0))?;
+            }
+            println!("Keysend sent! Preimage: {}", res?.preimage);
+        }
+        Nip47Subcommand::MultiPay { file, uri } => {
+            let (nwc_uri, wallet_name) =
+                resolve_wallet(uri, command.wallet.as_ref(), &command.common)?;
+            let invoices = read_invoice_batch(&file)?;
+            let amounts = invoices
+                .iter()
+                .map(|item| {
+                    item.amount
+                        .or_else(|| bolt11::decode(&item.invoice).ok()?.amount_msats)
+                        .unwrap_or(0)
+                })
+                .collect::<Vec<_>>();
+
+            let info = nwc_request(&nwc_uri, Request::get_info(), wait)
+                .await?
+                .to_get_info()?;
+            if let Some(name) = &wallet_name {
+                if amounts.contains(&0) {
+                    return Err(Error::Message(format!(
+                        "Cannot check the limits of wallet '{name}': some invoices have no amount"
+                    )));
+                }
+                reserve_payment(name, &load_config()?, &amounts)?;
+            }
+            let outcomes = if info.methods.iter().any(|m| m == "multi_pay_invoice") {
+                let total = amounts.iter().sum();
+                let outcomes = multi_pay(&nwc_uri, &invoices, wait).await;
+                if let (Some(name), Err(e)) = (&wallet_name, &outcomes) {
+                    settle_payment(name, total, Err(e))?;
+                }
+                let outcomes = outcomes?;
+                if let Some(name) = &wallet_name {
+                    for (item, &msats) in invoices.iter().zip(&amounts) {
+                        let outcome = match outcomes.get(item.id.as_deref().unwrap_or_default()) {
+                            Some(Ok(res)) => Ok(res.fees_paid.unwrap_or(0)),
+                            Some(Err(e)) => Err(RpcError::Remote(e.clone()).into()),
+                            None => Err(RpcError::Timeout(wait.as_secs()).into()),
+                        };
+                        settle_payment(name, msats, outcome.as_ref().copied())?;
+                    }
+                }
+                outcomes
+            } else {
+                eprintln!("Wallet does not support multi_pay_invoice, paying one by one");
+                let mut outcomes = HashMap::new();
+                for (item, &msats) in invoices.iter().zip(&amounts) {
+                    let res = nwc_request(&nwc_uri, Request::pay_invoice(item.clone()), wait)
+                        .await
+                        .and_then(|response| Ok(response.to_pay_invoice()?));
+                    if let Some(name) = &wallet_name {
+                        settle_payment(
+                            name,
+                            msats,
+                            res.as_ref().map(|res| res.fees_paid.unwrap_or(0)),
+                        )?;
                     }
+                    outcomes.insert(
+                        item.id.clone().unwrap_or_default(),
+                        res.map_err(|e| e.to_string()),
+                    );
                 }
-                None
+                outcomes
             };
 
-            match timeout(Duration::from_secs(30), fut).await {
-                Ok(Some(Ok(info))) => println!("Received info: {info:#?}"),
-                Ok(Some(Err(e))) => println!("Error from wallet: {e}"),
-                _ => println!("Timeout or no response."),
+            let mut table = Table::new();
+            table.load_preset(UTF8_FULL).set_header(vec![
+                "ID",
+                "Amount (sats)",
+                "Status",
+                "Preimage / Error",
+            ]);
+            let mut failed = 0;
+            for item in &invoices {
+                let id = item.id.clone().unwrap_or_default();
+                let amount = item
+                    .amount
+                    .map(|amount| (amount / 1000).to_string())
+                    .unwrap_or_default();
+                let (status, detail) = match outcomes.get(&id) {
+                    Some(Ok(res)) => ("paid", res.preimage.clone()),
+                    Some(Err(error)) => {
+                        failed += 1;
+                        ("failed", error.clone())
+                    }
+                    None => {
+                        failed += 1;
+                        ("failed", "no response".to_string())
+                    }
+                };
+                table.add_row(vec![
+                    Cell::new(id),
+                    Cell::new(amount),
+                    Cell::new(status),
+                    Cell::new(detail),
+                ]);
+            }
+            println!("{table}");
+            println!("{} paid, {failed} failed", invoices.len() - failed);
+            if failed > 0 {
+                return Err(Error::Message(format!(
+                    "{failed} of {} payments failed",
+                    invoices.len()
+                )));
             }
-
-            client.shutdown().await;
         }
-        Nip47Subcommand::GetBalance { uri } => {
-            let nwc_uri = NostrWalletConnectURI::parse(&uri)?;
+        Nip47Subcommand::MakeInvoice {
+            uri,
+            amount,
+            description,
+            expiry,
+        } => {
+            let nwc_uri = resolve_nwc_uri(uri, command.wallet.as_ref(), &command.common)?;
+
+            let params = MakeInvoiceRequest {
+                amount: sats_to_msats(amount)?,
+                description,
+                description_hash: None,
+                expiry,
+            };
+            let res = nwc_request(&nwc_uri, Request::make_invoice(params), wait)
+                .await?
+                .to_make_invoice()?;
+            println!("Invoice: {}", res.invoice);
+            println!("Payment hash: {}", res.payment_hash);
+        }
+        Nip47Subcommand::LookupInvoice {
+            uri,
+            payment_hash,
+            invoice,
+        } => {
+            let nwc_uri = resolve_nwc_uri(uri, command.wallet.as_ref(), &command.common)?;
+
+            let params = LookupInvoiceRequest {
+                payment_hash,
+                invoice,
+            };
+            let tx = nwc_request(&nwc_uri, Request::lookup_invoice(params), wait)
+                .await?
+                .to_lookup_invoice()?;
+            print_transaction(&tx);
+        }
+        Nip47Subcommand::ListTransactions {
+            uri,
+            from,
+            until,
+            limit,
+            transaction_type,
+            json,
+        } => {
+            let nwc_uri = resolve_nwc_uri(uri, command.wallet.as_ref(), &command.common)?;
+
+            let params = ListTransactionsRequest {
+                from: from.map(Timestamp::from),
+                until: until.map(Timestamp::from),
+                limit,
+                offset: None,
+                unpaid: None,
+                transaction_type: transaction_type.map(TransactionType::from),
+            };
+            let transactions = nwc_request(&nwc_uri, Request::list_transactions(params), wait)
+                .await?
+                .to_list_transactions()?;
 
-            let request = Request::get_balance();
-            let event = request.to_event(&nwc_uri)?;
+            if json {
+                for tx in &transactions {
+                    println!("{}", serde_json::to_string(tx)?);
+                }
+            } else {
+                let mut table = Table::new();
+                table.load_preset(UTF8_FULL).set_header(vec![
+                    "Created At",
+                    "Type",
+                    "Status",
+                    "Amount (sats)",
+                    "Fees (sats)",
+                    "Description",
+                    "Payment Hash",
+                ]);
+                for tx in &transactions {
+                    table.add_row(vec![
+                        Cell::new(tx.created_at.to_human_datetime()),
+                        Cell::new(transaction_type_name(tx)),
+                        Cell::new(transaction_status(tx)),
+                        Cell::new(tx.amount / 1000),
+                        Cell::new(tx.fees_paid / 1000),
+                        Cell::new(tx.description.clone().unwrap_or_default()),
+                        Cell::new(&tx.payment_hash),
+                    ]);
+                }
+                println!("{table}");
+                println!("{} transactions", transactions.len());
+            }
+        }
+        Nip47Subcommand::Serve(serve_command) => {
+            handle_serve_command(serve_command, &command.common).await?
+        }
+        Nip47Subcommand::Listen { uri, json, exec } => {
+            let nwc_uri = resolve_nwc_uri(uri, command.wallet.as_ref(), &command.common)?;
+            listen(&nwc_uri, json, exec.as_deref()).await?;
+        }
+    }
+    Ok(())
+}
 
-            let keys = Keys::new(nwc_uri.secret.clone());
-            let client = Client::new(keys);
+fn print_invoice(invoice: &bolt11::Invoice, msats: u64) {
+    println!("Amount: {} sats", msats.div_ceil(1000));
+    if let Some(description) = &invoice.description {
+        println!("Description: {description}");
+    } else if let Some(hash) = &invoice.description_hash {
+        println!("Description hash: {hash}");
+    }
+    println!("Payee: {}", invoice.payee);
+    println!(
+        "Expires at: {}",
+        Timestamp::from(invoice.expires_at()).to_human_datetime()
+    );
+}
 
-            for relay in nwc_uri.relays.iter() {
-                client.add_relay(relay.clone()).await?;
-            }
-            client.connect().await;
+fn transaction_type_name(tx: &LookupInvoiceResponse) -> &'static str {
+    match tx.transaction_type {
+        Some(TransactionType::Incoming) => "incoming",
+        Some(TransactionType::Outgoing) => "outgoing",
+        None => "",
+    }
+}
 
-            let event_id = client.send_event(&event).await?;
-            println!(
-                "GetBalance request sent with id: {}",
-                event_id.to_bech32().unwrap()
-            );
+fn transaction_status(tx: &LookupInvoiceResponse) -> &'static str {
+    if tx.settled_at.is_some() {
+        "settled"
+    } else if tx
+        .expires_at
+        .is_some_and(|expires_at| expires_at < Timestamp::now())
+    {
+        "expired"
+    } else {
+        "pending"
+    }
+}
 
-            // Handle response
-            let filter = Filter::new()
-                .kind(Kind::WalletConnectResponse)
-                .author(nwc_uri.public_key)
-                .event(*event_id)
-                .since(Timestamp::now());
-
-            client.subscribe(filter, None).await?;
-
-            println!("Waiting for response...");
-
-            let mut notifications = client.notifications();
-
-            let fut = async {
-                while let Ok(notification) = notifications.recv().await {
-                    if let RelayPoolNotification::Event { event, .. } = notification {
-                        if event.kind == Kind::WalletConnectResponse {
-                            if let Ok(response) = Response::from_event(&nwc_uri, &event) {
-                                match response.to_get_balance() {
-                                    Ok(balance) => return Some(Ok(balance)),
-                                    Err(e) => return Some(Err(Error::Message(e.to_string()))),
-                                }
-                            }
-                        }
-                    }
-                }
-                None
-            };
+fn print_transaction(tx: &LookupInvoiceResponse) {
+    println!("Type: {}", transaction_type_name(tx));
+    println!("Status: {}", transaction_status(tx));
+    println!("Amount: {} sats", tx.amount / 1000);
+    println!("Fees: {} sats", tx.fees_paid / 1000);
+    if let Some(description) = &tx.description {
+        println!("Description: {description}");
+    }
+    if let Some(invoice) = &tx.invoice {
+        println!("Invoice: {invoice}");
+    }
+    println!("Payment hash: {}", tx.payment_hash);
+    if let Some(preimage) = &tx.preimage {
+        println!("Preimage: {preimage}");
+    }
+    println!("Created at: {}", tx.created_at.to_human_datetime());
+    if let Some(expires_at) = tx.expires_at {
+        println!("Expires at: {}", expires_at.to_human_datetime());
+    }
+    if let Some(settled_at) = tx.settled_at {
+        println!("Settled at: {}", settled_at.to_human_datetime());
+    }
+}
 
-            match timeout(Duration::from_secs(30), fut).await {
-                Ok(Some(Ok(balance))) => {
-                    println!("Received balance: {} sats", balance.balance / 1000)
-                }
-                Ok(Some(Err(e))) => println!("Error from wallet: {e}"),
-                _ => println!("Timeout or no response."),
+/// Print wallet notifications until the relays go away, running `hook` for each of them.
+async fn listen(
+    nwc_uri: &NostrWalletConnectURI,
+    json: bool,
+    hook: Option<&str>,
+) -> Result<(), Error> {
+    let client = nwc_client(nwc_uri).await?;
+    let filter = Filter::new()
+        .kinds([
+            Kind::WalletConnectNotification,
+            WALLET_CONNECT_NOTIFICATION_NIP44,
+        ])
+        .author(nwc_uri.public_key)
+        .pubkey(Keys::new(nwc_uri.secret.clone()).public_key())
+        .since(Timestamp::now());
+
+    let mut notifications = client.notifications();
+    client.subscribe(filter, None).await?;
+    eprintln!("Listening for wallet notifications...");
+
+    // Wallets may publish each notification twice, once per encryption scheme
+    let mut seen = HashSet::new();
+    loop {
+        let notification = match notifications.recv().await {
+            Ok(notification) => notification,
+            Err(RecvError::Lagged(skipped)) => {
+                eprintln!("Fell behind and missed {skipped} relay messages");
+                continue;
             }
+            Err(RecvError::Closed) => {
+                return Err(Error::Message(
+                    "Stopped receiving notifications from the relays".to_string(),
+                ));
+            }
+        };
+        let RelayPoolNotification::Event { event, .. } = notification else {
+            continue;
+        };
+        let notification = match decrypt_notification(nwc_uri, &event) {
+            Ok(notification) => notification,
+            Err(e) => {
+                eprintln!("Skipping notification {}: {e}", event.id);
+                continue;
+            }
+        };
+        let line = serde_json::to_string(&notification)?;
+        let notification_type = notification.notification_type.to_string();
+        let payment = notification.to_pay_notification()?;
+        if !seen.insert((notification_type.clone(), payment.payment_hash.clone())) {
+            continue;
+        }
 
-            client.shutdown().await;
+        if json {
+            println!("{line}");
+        } else {
+            print_notification(&notification_type, &payment);
+        }
+        if let Some(hook) = hook {
+            // The hook may take a while; keep it off the runtime's worker threads
+            let hook = hook.to_string();
+            tokio::task::spawn_blocking(move || {
+                run_hook(&hook, &notification_type, &payment, &line)
+            })
+            .await
+            .map_err(|e| Error::Message(format!("Hook task failed: {e}")))?;
         }
-        Nip47Subcommand::PayInvoice { uri, invoice } => {
-            let nwc_uri = NostrWalletConnectURI::parse(&uri)?;
+    }
+}
 
-            let params = PayInvoiceRequest {
-                id: None,
-                invoice,
-                amount: None,
-            };
-            let request = Request::pay_invoice(params);
-            let event = request.to_event(&nwc_uri)?;
+fn decrypt_notification(
+    nwc_uri: &NostrWalletConnectURI,
+    event: &Event,
+) -> Result<Notification, Error> {
+    let content = if event.kind == WALLET_CONNECT_NOTIFICATION_NIP44 {
+        nip44::decrypt(&nwc_uri.secret, &event.pubkey, &event.content)?
+    } else {
+        nip04::decrypt(&nwc_uri.secret, &event.pubkey, &event.content)?
+    };
+    Ok(Notification::from_json(content)?)
+}
+
+fn print_notification(notification_type: &str, payment: &PaymentNotification) {
+    let mut line = format!(
+        "{} {notification_type} {} sats",
+        payment.settled_at.to_human_datetime(),
+        payment.amount / 1000
+    );
+    if payment.fees_paid > 0 {
+        line.push_str(&format!(" (fees {} sats)", payment.fees_paid / 1000));
+    }
+    line.push_str(&format!(" {}", payment.payment_hash));
+    if let Some(description) = &payment.description {
+        line.push_str(&format!(" {description}"));
+    }
+    println!("{line}");
+}
+
+/// Run `hook` through the shell with the notification JSON on stdin. Failures are only reported,
+/// so one broken hook run does not stop the listener.
+fn run_hook(hook: &str, notification_type: &str, payment: &PaymentNotification, json: &str) {
+    let child = Command::new("sh")
+        .arg("-c")
+        .arg(hook)
+        .env("NWC_NOTIFICATION_TYPE", notification_type)
+        .env("NWC_PAYMENT_HASH", &payment.payment_hash)
+        .env("NWC_AMOUNT_MSATS", payment.amount.to_string())
+        .stdin(Stdio::piped())
+        .spawn();
+    let result = child.and_then(|mut child| {
+        if let Some(mut stdin) = child.stdin.take() {
+            writeln!(stdin, "{json}")?;
+        }
+        child.wait()
+    });
+    match result {
+        Ok(status) if status.success() => {}
+        Ok(status) => eprintln!("Hook for {} exited with {status}", payment.payment_hash),
+        Err(e) => eprintln!("Could not run hook for {}: {e}", payment.payment_hash),
+    }
+}
 
-            let keys = Keys::new(nwc_uri.secret.clone());
-            let client = Client::new(keys);
+/// Connect to the relays of `nwc_uri` as the connection's client key.
+async fn nwc_client(nwc_uri: &NostrWalletConnectURI) -> Result<Client, Error> {
+    let client = Client::new(Keys::new(nwc_uri.secret.clone()));
+    for relay in nwc_uri.relays.iter() {
+        client.add_relay(relay.clone()).await?;
+    }
+    client.connect().await;
+    Ok(client)
+}
 
-            for relay in nwc_uri.relays.iter() {
-                client.add_relay(relay.clone()).await?;
+/// Send `request` to the wallet service of `nwc_uri` and wait for its response.
+pub async fn nwc_request(
+    nwc_uri: &NostrWalletConnectURI,
+    request: Request,
+    wait: Duration,
+) -> Result<Response, Error> {
+    let method = request.method;
+    let event = request.to_event(nwc_uri)?;
+    let client = nwc_client(nwc_uri).await?;
+
+    eprintln!(
+        "Sending {method} request with id: {}",
+        event.id.to_bech32().unwrap()
+    );
+    eprintln!("Waiting for response...");
+
+    let result = rpc::call(
+        &client,
+        &event,
+        Kind::WalletConnectResponse,
+        nwc_uri.public_key,
+        Match::EventTag,
+        wait,
+        |event| {
+            let response = Response::from_event(nwc_uri, event)
+                .map_err(|e| RpcError::Decrypt(e.to_string()))?;
+            match response.error {
+                Some(error) => Err(RpcError::Remote(error.to_string()).into()),
+                None => Ok(Some(response)),
             }
-            client.connect().await;
+        },
+    )
+    .await;
 
-            let event_id = client.send_event(&event).await?;
-            println!(
-                "PayInvoice request sent with id: {}",
-                event_id.to_bech32().unwrap()
-            );
+    client.shutdown().await;
+    result
+}
 
-            // Handle response
-            let filter = Filter::new()
-                .kind(Kind::WalletConnectResponse)
-                .author(nwc_uri.public_key)
-                .event(*event_id)
-                .since(Timestamp::now());
-
-            client.subscribe(filter, None).await?;
-
-            println!("Waiting for response...");
-
-            let mut notifications = client.notifications();
-
-            let fut = async {
-                while let Ok(notification) = notifications.recv().await {
-                    if let RelayPoolNotification::Event { event, .. } = notification {
-                        if event.kind == Kind::WalletConnectResponse {
-                            if let Ok(response) = Response::from_event(&nwc_uri, &event) {
-                                match response.to_pay_invoice() {
-                                    Ok(res) => return Some(Ok(res)),
-                                    Err(e) => return Some(Err(Error::Message(e.to_string()))),
-                                }
-                            }
-                        }
-                    }
-                }
-                None
+/// Send one `multi_pay_invoice` request and collect the per-invoice responses by id.
+///
+/// Invoices without a response before the timeout are missing from the result.
+async fn multi_pay(
+    nwc_uri: &NostrWalletConnectURI,
+    invoices: &[PayInvoiceRequest],
+    wait: Duration,
+) -> Result<HashMap<String, Result<PayInvoiceResponse, String>>, Error> {
+    let request = Request::multi_pay_invoice(MultiPayInvoiceRequest {
+        invoices: invoices.to_vec(),
+    });
+    let event = request.to_event(nwc_uri)?;
+    let client = nwc_client(nwc_uri).await?;
+
+    eprintln!(
+        "Sending multi_pay_invoice request for {} invoices with id: {}",
+        invoices.len(),
+        event.id.to_bech32().unwrap()
+    );
+    eprintln!("Waiting for responses...");
+
+    let mut outcomes = HashMap::new();
+    let result = rpc::call(
+        &client,
+        &event,
+        Kind::WalletConnectResponse,
+        nwc_uri.public_key,
+        Match::EventTag,
+        wait,
+        |event| {
+            // Each invoice gets its own response, tagged with the invoice id
+            let Some(id) = event.tags.identifier() else {
+                return Ok(None);
+            };
+            let response = Response::from_event(nwc_uri, event)
+                .map_err(|e| RpcError::Decrypt(e.to_string()))?;
+            let outcome = match (response.error, response.result) {
+                (Some(error), _) => Err(error.to_string()),
+                (
+                    None,
+                    Some(ResponseResult::MultiPayInvoice(res) | ResponseResult::PayInvoice(res)),
+                ) => Ok(res),
+                (None, _) => Err("unexpected response".to_string()),
             };
+            outcomes.insert(id.to_string(), outcome);
+            Ok((outcomes.len() == invoices.len()).then_some(()))
+        },
+    )
+    .await;
+    client.shutdown().await;
+
+    match result {
+        Ok(()) | Err(Error::Rpc(RpcError::Timeout(_))) => Ok(outcomes),
+        Err(e) => Err(e),
+    }
+}
 
-            match timeout(Duration::from_secs(30), fut).await {
-                Ok(Some(Ok(res))) => println!("Invoice paid! Preimage: {}", res.preimage),
-                Ok(Some(Err(e))) => println!("Error from wallet: {e}"),
-                _ => println!("Timeout or no response."),
-            }
+#[derive(Deserialize)]
+struct BatchInvoice {
+    invoice: String,
+    /// Amount in sats, for invoices without one
+    amount: Option<u64>,
+    id: Option<String>,
+}
 
-            client.shutdown().await;
-        }
+/// Read a batch of invoices and give every entry an id so responses can be matched.
+fn read_invoice_batch(path: &str) -> Result<Vec<PayInvoiceRequest>, Error> {
+    let content = fs::read_to_string(path)?;
+    let entries: Vec<BatchInvoice> = if content.trim_start().starts_with('[') {
+        serde_json::from_str(&content)?
+    } else {
+        content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let mut parts = line.split_whitespace();
+                let invoice = parts.next().unwrap_or_default().to_string();
+                let amount = parts
+                    .next()
+                    .map(|amount| {
+                        amount
+                            .parse()
+                            .map_err(|_| Error::Message(format!("Invalid amount in line: {line}")))
+                    })
+                    .transpose()?;
+                Ok(BatchInvoice {
+                    invoice,
+                    amount,
+                    id: None,
+                })
+            })
+            .collect::<Result<_, Error>>()?
+    };
+    if entries.is_empty() {
+        return Err(Error::Message(format!("No invoices found in {path}")));
     }
-    Ok(())
+
+    entries
+        .into_iter()
+        .enumerate()
+        .map(|(i, entry)| {
+            Ok(PayInvoiceRequest {
+                id: Some(entry.id.unwrap_or_else(|| (i + 1).to_string())),
+                invoice: entry.invoice,
+                amount: entry.amount.map(sats_to_msats).transpose()?,
+            })
+        })
+        .collect()
+}
+
+/// Parse a keysend TLV record given as `<type>=<hex value>`.
+fn parse_tlv(record: &str) -> Result<KeysendTLVRecord, Error> {
+    let (tlv_type, value) = record.split_once('=').ok_or_else(|| {
+        Error::Message(format!(
+            "Invalid TLV record '{record}', expected <type>=<hex value>"
+        ))
+    })?;
+    let tlv_type = tlv_type
+        .parse()
+        .map_err(|_| Error::Message(format!("Invalid TLV type in '{record}'")))?;
+    if value.is_empty() || !value.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(Error::Message(format!(
+            "TLV value in '{record}' must be hex"
+        )));
+    }
+    Ok(KeysendTLVRecord {
+        tlv_type,
+        value: value.to_string(),
+    })
 }
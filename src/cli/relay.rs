@@ -1,13 +1,14 @@
-use crate::cli::common::{connect_client, get_relays, get_secret_key};
+use crate::cli::common::{connect_client, get_relays, get_signer};
 use crate::cli::CommonOptions;
 use crate::config::load_config;
 use crate::error::Error;
 use clap::{Parser, Subcommand};
 use dialoguer::{theme::ColorfulTheme, Confirm, Input, MultiSelect, Select};
 use nostr::prelude::FromBech32;
-use nostr::{Keys, SecretKey};
+use nostr::Keys;
 use nostr_sdk::prelude::*;
 use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::Duration;
 
 #[derive(Parser, Clone)]
@@ -43,27 +44,27 @@ pub async fn handle_relay_command(command: RelayCommand) -> Result<(), Error> {
         RelaySubcommand::Set {
             relays: relays_to_set,
         } => {
-            let secret_key_str = get_secret_key(&command.common, &config)?;
-            set_relays(relays_to_set, secret_key_str, relays).await?;
+            let signer = get_signer(&command.common, &config)?;
+            set_relays(relays_to_set, signer, relays).await?;
         }
         RelaySubcommand::Get { pubkey } => {
             get_relays_list(pubkey, relays).await?;
         }
         RelaySubcommand::Edit => {
-            let secret_key_str = get_secret_key(&command.common, &config)?;
-            edit_relays(secret_key_str, relays).await?;
+            let signer = get_signer(&command.common, &config)?;
+            edit_relays(signer, relays).await?;
         }
     }
     Ok(())
 }
 
-pub async fn edit_relays(secret_key_str: String, relays: Vec<String>) -> Result<(), Error> {
-    let keys = Keys::new(SecretKey::from_bech32(&secret_key_str)?);
-    let client = connect_client(keys.clone(), relays.clone()).await?;
+pub async fn edit_relays(signer: Arc<dyn NostrSigner>, relays: Vec<String>) -> Result<(), Error> {
+    let public_key = signer.get_public_key().await?;
+    let client = connect_client(signer, relays.clone()).await?;
 
     // Fetch existing relay list
     let filter = Filter::new()
-        .author(keys.public_key())
+        .author(public_key)
         .kind(Kind::RelayList)
         .limit(1);
     let timeout = Duration::from_secs(10);
@@ -76,12 +77,12 @@ pub async fn edit_relays(secret_key_str: String, relays: Vec<String>) -> Result<
     if let Some(event) = events.first() {
         for tag in event.tags.iter() {
             let tag_vec = tag.clone().to_vec();
-            if tag_vec.first().map(|s| s.as_str()) == Some("r") {
-                if let Some(url) = tag_vec.get(1) {
-                    let markers = relay_markers.entry(url.clone()).or_default();
-                    if let Some(marker) = tag_vec.get(2) {
-                        markers.push(marker.clone());
-                    }
+            if tag_vec.first().map(|s| s.as_str()) == Some("r")
+                && let Some(url) = tag_vec.get(1)
+            {
+                let markers = relay_markers.entry(url.clone()).or_default();
+                if let Some(marker) = tag_vec.get(2) {
+                    markers.push(marker.clone());
                 }
             }
         }
@@ -283,11 +284,10 @@ async fn get_relays_list(pubkey: String, relays: Vec<String>) -> Result<(), Erro
 
 async fn set_relays(
     relays_to_set: Vec<String>,
-    secret_key_str: String,
+    signer: Arc<dyn NostrSigner>,
     relays: Vec<String>,
 ) -> Result<(), Error> {
-    let keys = Keys::new(SecretKey::from_bech32(&secret_key_str)?);
-    let client = connect_client(keys, relays).await?;
+    let client = connect_client(signer, relays).await?;
 
     let mut tags = Vec::new();
     for r in relays_to_set {
@@ -0,0 +1,400 @@
+use crate::cli::common::decrypt_secret_key;
+use crate::config::{
+    Config, DailySpending, Spending, Wallet, load_config, load_spending, save_config, save_spending,
+};
+use crate::error::Error;
+use crate::rpc::RpcError;
+use clap::{Parser, Subcommand};
+use colored::*;
+use comfy_table::{Cell, Table, presets::UTF8_FULL};
+use dialoguer::{Confirm, Password, theme::ColorfulTheme};
+use nostr::nips::nip47::NostrWalletConnectURI;
+use nostr_sdk::prelude::*;
+use std::io::{self, BufRead, IsTerminal};
+
+#[derive(Parser, Clone)]
+pub struct WalletCommand {
+    #[command(subcommand)]
+    subcommand: WalletSubcommand,
+}
+
+#[derive(Subcommand, Clone)]
+enum WalletSubcommand {
+    /// List stored wallet connections
+    List,
+    /// Store a Wallet Connect URI, read from a prompt or stdin
+    Add {
+        /// Name of the wallet
+        name: String,
+        /// Take the plaintext URI stored in this identity instead and point the identity at
+        /// the new wallet
+        #[clap(long)]
+        from_identity: Option<String>,
+    },
+    /// Remove a stored wallet connection
+    Remove {
+        /// Name of the wallet
+        name: String,
+    },
+    /// Set the wallet used when no URI or --wallet is given
+    Default {
+        /// Name of the wallet
+        name: String,
+    },
+    /// Show or set the payment limits of a wallet
+    Limit {
+        /// Name of the wallet
+        name: String,
+        /// Largest single payment in sats, 0 removes the limit
+        #[clap(long)]
+        max_payment: Option<u64>,
+        /// Sats that may be paid per day (UTC), 0 removes the limit
+        #[clap(long)]
+        daily_limit: Option<u64>,
+    },
+}
+
+pub async fn handle_wallet_command(command: WalletCommand) -> Result<(), Error> {
+    let mut config = load_config()?;
+
+    match command.subcommand {
+        WalletSubcommand::List => {
+            if config.wallets.is_empty() {
+                println!("No wallets configured. Run `nip47 wallet add <name>` to add one.");
+                return Ok(());
+            }
+            let mut table = Table::new();
+            table.load_preset(UTF8_FULL).set_header(vec![
+                "",
+                "Name",
+                "Wallet Public Key",
+                "Relays",
+                "LUD-16",
+            ]);
+            for (name, wallet) in &config.wallets {
+                let is_default = config.default_wallet.as_ref() == Some(name);
+                table.add_row(vec![
+                    Cell::new(if is_default { "*" } else { "" }),
+                    Cell::new(name),
+                    Cell::new(&wallet.public_key),
+                    Cell::new(wallet.relays.join("\n")),
+                    Cell::new(wallet.lud16.clone().unwrap_or_default()),
+                ]);
+            }
+            println!("{table}");
+        }
+        WalletSubcommand::Add {
+            name,
+            from_identity,
+        } => {
+            if config.wallets.contains_key(&name) {
+                return Err(Error::Message(format!("Wallet '{name}' already exists")));
+            }
+
+            let theme = ColorfulTheme::default();
+            // Never take the URI as an argument: it contains the secret and would end up in the shell history
+            let uri = if let Some(identity) = &from_identity {
+                config
+                    .identities
+                    .get(identity)
+                    .ok_or(Error::Message(format!(
+                        "Identity '{identity}' not found in config"
+                    )))?
+                    .nwc_uri
+                    .clone()
+                    .ok_or(Error::Message(format!(
+                        "Identity '{identity}' has no Wallet Connect URI"
+                    )))?
+            } else if io::stdin().is_terminal() {
+                Password::with_theme(&theme)
+                    .with_prompt("Wallet Connect URI (nostr+walletconnect://...)")
+                    .interact()?
+            } else {
+                let mut line = String::new();
+                io::stdin().lock().read_line(&mut line)?;
+                line.trim().to_string()
+            };
+            let uri = NostrWalletConnectURI::parse(&uri)?;
+
+            let password = Password::with_theme(&theme)
+                .with_prompt("Enter a password to encrypt the connection secret")
+                .with_confirmation("Confirm password", "Passwords do not match")
+                .interact()?;
+            let encrypted_secret = uri.secret.encrypt(&password)?;
+
+            config.wallets.insert(
+                name.clone(),
+                Wallet {
+                    public_key: uri.public_key.to_hex(),
+                    relays: uri.relays.iter().map(|r| r.to_string()).collect(),
+                    lud16: uri.lud16.clone(),
+                    encrypted_secret: encrypted_secret.to_bech32()?,
+                    max_payment: None,
+                    daily_limit: None,
+                },
+            );
+            if config.default_wallet.is_none() {
+                config.default_wallet = Some(name.clone());
+            }
+            if let Some(identity) = from_identity.and_then(|i| config.identities.get_mut(&i)) {
+                identity.nwc_uri = None;
+                identity.wallet = Some(name.clone());
+            }
+            save_config(&config)?;
+            println!("Wallet '{name}' added.");
+        }
+        WalletSubcommand::Remove { name } => {
+            if !config.wallets.contains_key(&name) {
+                return Err(Error::Message(format!(
+                    "Wallet '{name}' not found in config"
+                )));
+            }
+            if !Confirm::with_theme(&ColorfulTheme::default())
+                .with_prompt(format!("Remove wallet '{name}'?"))
+                .default(false)
+                .interact()?
+            {
+                println!("Removal cancelled.");
+                return Ok(());
+            }
+            config.wallets.remove(&name);
+            if config.default_wallet.as_ref() == Some(&name) {
+                config.default_wallet = None;
+                println!("{}", "The default wallet was removed.".yellow());
+            }
+            for (identity_name, identity) in &mut config.identities {
+                if identity.wallet.as_ref() == Some(&name) {
+                    identity.wallet = None;
+                    println!(
+                        "{}",
+                        format!("Identity '{identity_name}' no longer has a wallet.").yellow()
+                    );
+                }
+            }
+            save_config(&config)?;
+            println!("Wallet '{name}' removed.");
+        }
+        WalletSubcommand::Default { name } => {
+            if !config.wallets.contains_key(&name) {
+                return Err(Error::Message(format!(
+                    "Wallet '{name}' not found in config"
+                )));
+            }
+            config.default_wallet = Some(name.clone());
+            save_config(&config)?;
+            println!("Default wallet set to '{name}'.");
+        }
+        WalletSubcommand::Limit {
+            name,
+            max_payment,
+            daily_limit,
+        } => {
+            let wallet = config
+                .wallets
+                .get_mut(&name)
+                .ok_or_else(|| Error::Message(format!("Wallet '{name}' not found in config")))?;
+            if max_payment.is_some() || daily_limit.is_some() {
+                if let Some(sats) = max_payment {
+                    wallet.max_payment = (sats > 0).then_some(sats);
+                }
+                if let Some(sats) = daily_limit {
+                    wallet.daily_limit = (sats > 0).then_some(sats);
+                }
+                let wallet = wallet.clone();
+                save_config(&config)?;
+                println!("Limits of wallet '{name}' updated.");
+                print_limits(&name, &wallet)?;
+            } else {
+                print_limits(&name, wallet)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn print_limits(name: &str, wallet: &Wallet) -> Result<(), Error> {
+    let limit = |sats: Option<u64>| sats.map_or("none".to_string(), |sats| format!("{sats} sats"));
+    println!("Max payment: {}", limit(wallet.max_payment));
+    println!("Daily limit: {}", limit(wallet.daily_limit));
+    println!(
+        "Paid today: {} sats",
+        spent_today(&load_spending()?, name) / 1000
+    );
+    Ok(())
+}
+
+fn today() -> u64 {
+    Timestamp::now().as_u64() / 86_400
+}
+
+fn spent_today(spending: &Spending, name: &str) -> u64 {
+    match spending.wallets.get(name) {
+        Some(spent) if spent.day == today() => spent.msats,
+        _ => 0,
+    }
+}
+
+/// Refuse a payment of `msats` from wallet `name` that would break its limits.
+pub fn check_limits(name: &str, config: &Config, msats: u64) -> Result<(), Error> {
+    check_batch_limits(name, config, &[msats])
+}
+
+/// Refuse a batch of payments from wallet `name` if one of them exceeds the per-payment limit
+/// or all of them together the daily limit.
+pub fn check_batch_limits(name: &str, config: &Config, payments: &[u64]) -> Result<(), Error> {
+    let Some(wallet) = config.wallets.get(name) else {
+        return Ok(());
+    };
+    let spent = match wallet.daily_limit {
+        Some(_) => spent_today(&load_spending()?, name),
+        None => 0,
+    };
+    within_limits(name, wallet, spent, payments)
+}
+
+fn within_limits(name: &str, wallet: &Wallet, spent: u64, payments: &[u64]) -> Result<(), Error> {
+    if let Some(max) = wallet.max_payment
+        && let Some(msats) = payments
+            .iter()
+            .find(|&&msats| msats > max.saturating_mul(1000))
+    {
+        return Err(Error::Message(format!(
+            "Payment of {} sats exceeds the {max} sats per-payment limit of wallet '{name}'",
+            msats.div_ceil(1000)
+        )));
+    }
+    let total = payments
+        .iter()
+        .fold(0u64, |total, &msats| total.saturating_add(msats));
+    if let Some(daily) = wallet.daily_limit
+        && spent.saturating_add(total) > daily.saturating_mul(1000)
+    {
+        return Err(Error::Message(format!(
+            "Payment of {} sats exceeds the {daily} sats daily limit of wallet '{name}' ({} sats already paid today)",
+            total.div_ceil(1000),
+            spent / 1000
+        )));
+    }
+    Ok(())
+}
+
+/// Add a payment to today's total of wallet `name`.
+pub fn record_payment(name: &str, msats: u64) -> Result<(), Error> {
+    let mut spending = load_spending()?;
+    let spent = spent_today(&spending, name);
+    set_spent_today(&mut spending, name, spent.saturating_add(msats));
+    save_spending(&spending)
+}
+
+/// Take a payment that the wallet refused back out of today's total of wallet `name`.
+fn release_payment(name: &str, msats: u64) -> Result<(), Error> {
+    let mut spending = load_spending()?;
+    let spent = spent_today(&spending, name);
+    set_spent_today(&mut spending, name, spent.saturating_sub(msats));
+    save_spending(&spending)
+}
+
+fn set_spent_today(spending: &mut Spending, name: &str, msats: u64) {
+    spending.wallets.insert(
+        name.to_string(),
+        DailySpending {
+            day: today(),
+            msats,
+        },
+    );
+}
+
+/// Check the limits of wallet `name` and count `payments` as spent before sending them, so
+/// a payment whose response never arrives still counts. Hand the outcome to
+/// [`settle_payment`] afterwards.
+pub fn reserve_payment(name: &str, config: &Config, payments: &[u64]) -> Result<(), Error> {
+    check_batch_limits(name, config, payments)?;
+    record_payment(name, payments.iter().sum())
+}
+
+/// Settle a payment of `msats` reserved with [`reserve_payment`]: add the fees once it went
+/// through, or give it back if the wallet refused it or never got the request. Any other
+/// failure leaves it counted, as the wallet may have paid anyway.
+pub fn settle_payment(name: &str, msats: u64, outcome: Result<u64, &Error>) -> Result<(), Error> {
+    match outcome {
+        Ok(fees) => record_payment(name, fees),
+        Err(Error::Rpc(RpcError::Remote(_) | RpcError::Send(_))) => release_payment(name, msats),
+        Err(_) => {
+            eprintln!(
+                "{}",
+                format!(
+                    "The wallet may have paid {} sats anyway; they stay counted against the limits of wallet '{name}'.",
+                    msats.div_ceil(1000)
+                )
+                .yellow()
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Rebuild the Wallet Connect URI of a stored wallet, asking for the password to its secret.
+pub fn wallet_uri(name: &str, config: &Config) -> Result<NostrWalletConnectURI, Error> {
+    let wallet = config
+        .wallets
+        .get(name)
+        .ok_or_else(|| Error::Message(format!("Wallet '{name}' not found in config")))?;
+
+    let secret = decrypt_secret_key(
+        &wallet.encrypted_secret,
+        &format!("Password for wallet '{name}'"),
+    )?;
+    let relays = wallet
+        .relays
+        .iter()
+        .map(|url| RelayUrl::parse(url))
+        .collect::<Result<_, _>>()?;
+
+    Ok(NostrWalletConnectURI::new(
+        PublicKey::from_hex(&wallet.public_key)?,
+        relays,
+        SecretKey::parse(&secret)?,
+        wallet.lud16.clone(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_cover_each_payment_and_the_daily_total() {
+        let wallet = Wallet {
+            max_payment: Some(50),
+            daily_limit: Some(100),
+            ..Default::default()
+        };
+
+        assert!(within_limits("w", &wallet, 0, &[50_000]).is_ok());
+        assert!(within_limits("w", &wallet, 0, &[50_001]).is_err());
+        // Every payment of a batch is within the per-payment limit, but not all of them today
+        assert!(within_limits("w", &wallet, 0, &[40_000, 40_000]).is_ok());
+        assert!(within_limits("w", &wallet, 30_000, &[40_000, 40_000]).is_err());
+        assert!(within_limits("w", &Wallet::default(), u64::MAX / 2, &[1_000_000]).is_ok());
+
+        // Limits and totals past u64::MAX msats saturate instead of wrapping around
+        let huge = Wallet {
+            max_payment: Some(u64::MAX),
+            daily_limit: Some(u64::MAX / 1000),
+            ..Default::default()
+        };
+        assert!(within_limits("w", &huge, 0, &[u64::MAX / 2]).is_ok());
+        assert!(within_limits("w", &huge, u64::MAX / 2, &[u64::MAX / 2, u64::MAX / 2]).is_err());
+    }
+
+    #[test]
+    fn spending_resets_every_day() {
+        let mut spending = Spending::default();
+        set_spent_today(&mut spending, "w", 21_000);
+        assert_eq!(spent_today(&spending, "w"), 21_000);
+        assert_eq!(spent_today(&spending, "other"), 0);
+
+        spending.wallets.get_mut("w").unwrap().day -= 1;
+        assert_eq!(spent_today(&spending, "w"), 0);
+    }
+}
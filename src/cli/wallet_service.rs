@@ -0,0 +1,824 @@
+//! `nip47 serve`: a Nostr Wallet Connect service backed by a local JSON ledger.
+//!
+//! Nothing here touches Lightning. Invoices are BOLT-11 shaped (`lnbcrt...`) and signed with the
+//! service key. Paying an invoice made by the same ledger settles it internally; any other
+//! invoice is simply debited and answered with a made-up preimage.
+
+use crate::bolt11;
+use crate::cli::CommonOptions;
+use crate::cli::common::{connect_client, decrypt_message, get_relays, is_nip04, sats_to_msats};
+use crate::cli::nip47::WALLET_CONNECT_NOTIFICATION_NIP44;
+use crate::config::{get_config_path, load_config, write_atomically};
+use crate::error::Error;
+use clap::{Parser, Subcommand, ValueEnum};
+use comfy_table::{Cell, Table, presets::UTF8_FULL};
+use nostr::hashes::{Hash, sha256};
+use nostr::nips::nip47::{
+    ErrorCode, GetBalanceResponse, GetInfoResponse, ListTransactionsRequest, LookupInvoiceRequest,
+    LookupInvoiceResponse, MakeInvoiceRequest, MakeInvoiceResponse, Method, NIP47Error,
+    NostrWalletConnectURI, Notification, NotificationResult, NotificationType, PayInvoiceRequest,
+    PayInvoiceResponse, PaymentNotification, Request, RequestParams, ResponseResult,
+    TransactionType,
+};
+use nostr_sdk::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tokio::sync::broadcast::error::RecvError;
+
+/// Methods answered by the service, advertised in the info event and `get_info`.
+const METHODS: [Method; 6] = [
+    Method::GetInfo,
+    Method::GetBalance,
+    Method::MakeInvoice,
+    Method::PayInvoice,
+    Method::LookupInvoice,
+    Method::ListTransactions,
+];
+
+const NOTIFICATIONS: [NotificationType; 2] = [
+    NotificationType::PaymentReceived,
+    NotificationType::PaymentSent,
+];
+
+/// Currency of the invoices the service makes, so they can never be mistaken for mainnet ones.
+const INVOICE_CURRENCY: &str = "bcrt";
+
+#[derive(Parser, Clone)]
+pub struct ServeCommand {
+    /// Ledger file (JSON), defaults to ledger.json next to the config
+    #[clap(long)]
+    ledger: Option<PathBuf>,
+    #[command(subcommand)]
+    action: Option<ServeAction>,
+}
+
+#[derive(Subcommand, Clone)]
+enum ServeAction {
+    /// Create a client connection and print its Wallet Connect URI
+    Connect {
+        /// Name of the connection
+        name: String,
+        /// Sats the connection may spend per renewal period
+        #[clap(long)]
+        budget: Option<u64>,
+        /// How often the budget starts over
+        #[clap(long, value_enum, default_value_t = Renewal::Never)]
+        renewal: Renewal,
+    },
+    /// List client connections and their budgets
+    Connections,
+    /// Add sats to the ledger balance
+    Deposit {
+        /// Amount in sats
+        amount: u64,
+    },
+}
+
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+enum Renewal {
+    #[default]
+    Never,
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+impl Renewal {
+    fn period_secs(self) -> Option<u64> {
+        match self {
+            Renewal::Never => None,
+            Renewal::Daily => Some(86_400),
+            Renewal::Weekly => Some(7 * 86_400),
+            Renewal::Monthly => Some(30 * 86_400),
+            Renewal::Yearly => Some(365 * 86_400),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Ledger {
+    /// Secret key (hex) of the wallet service, also signing its invoices
+    service_secret: String,
+    /// Balance in millisatoshis, shared by all connections
+    balance: u64,
+    #[serde(default)]
+    connections: BTreeMap<String, Connection>,
+    #[serde(default)]
+    transactions: Vec<LedgerEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Connection {
+    /// Public key (hex) the client signs its requests with
+    public_key: String,
+    /// Millisatoshis the connection may spend per renewal period
+    budget: Option<u64>,
+    #[serde(default)]
+    renewal: Renewal,
+    /// Millisatoshis spent in the current period
+    spent: u64,
+    period_start: u64,
+    created_at: u64,
+}
+
+impl Connection {
+    /// Start a new budget period once the current one is over.
+    fn renew(&mut self, now: u64) {
+        if let Some(period) = self.renewal.period_secs()
+            && now >= self.period_start + period
+        {
+            self.period_start = now - (now - self.period_start) % period;
+            self.spent = 0;
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct LedgerEntry {
+    /// Connection that made the invoice or the payment
+    connection: String,
+    #[serde(flatten)]
+    tx: LookupInvoiceResponse,
+}
+
+impl LedgerEntry {
+    /// The transaction as clients see it: preimages of unpaid invoices stay private.
+    fn view(&self) -> LookupInvoiceResponse {
+        let mut tx = self.tx.clone();
+        if tx.settled_at.is_none() {
+            tx.preimage = None;
+        }
+        tx
+    }
+
+    fn to_notification(&self) -> Option<Notification> {
+        let notification_type = match self.tx.transaction_type? {
+            TransactionType::Incoming => NotificationType::PaymentReceived,
+            TransactionType::Outgoing => NotificationType::PaymentSent,
+        };
+        let payment = PaymentNotification {
+            transaction_type: self.tx.transaction_type,
+            invoice: self.tx.invoice.clone().unwrap_or_default(),
+            description: self.tx.description.clone(),
+            description_hash: self.tx.description_hash.clone(),
+            preimage: self.tx.preimage.clone()?,
+            payment_hash: self.tx.payment_hash.clone(),
+            amount: self.tx.amount,
+            fees_paid: self.tx.fees_paid,
+            created_at: self.tx.created_at,
+            expires_at: self.tx.expires_at,
+            settled_at: self.tx.settled_at?,
+            metadata: None,
+        };
+        Some(Notification {
+            notification_type,
+            notification: match notification_type {
+                NotificationType::PaymentReceived => NotificationResult::PaymentReceived(payment),
+                NotificationType::PaymentSent => NotificationResult::PaymentSent(payment),
+            },
+        })
+    }
+}
+
+fn nip47_error(code: ErrorCode, message: impl Into<String>) -> NIP47Error {
+    NIP47Error {
+        code,
+        message: message.into(),
+    }
+}
+
+/// 32 random bytes, hex encoded, with their SHA-256 as the payment hash.
+fn random_preimage() -> (String, String) {
+    let bytes = SecretKey::generate().secret_bytes();
+    let preimage = bolt11::hex(&bytes);
+    (preimage, sha256::Hash::hash(&bytes).to_string())
+}
+
+impl Ledger {
+    fn new() -> Self {
+        Ledger {
+            service_secret: SecretKey::generate().to_secret_hex(),
+            balance: 0,
+            connections: BTreeMap::new(),
+            transactions: Vec::new(),
+        }
+    }
+
+    fn load(path: &Path) -> Result<Self, Error> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    fn save(&self, path: &Path) -> Result<(), Error> {
+        write_atomically(path, &serde_json::to_string_pretty(self)?)
+    }
+
+    fn keys(&self) -> Result<Keys, Error> {
+        Ok(Keys::new(SecretKey::from_hex(&self.service_secret)?))
+    }
+
+    fn connection_of(&self, public_key: &PublicKey) -> Option<String> {
+        let public_key = public_key.to_hex();
+        self.connections
+            .iter()
+            .find(|(_, connection)| connection.public_key == public_key)
+            .map(|(name, _)| name.clone())
+    }
+
+    /// Answer one request of connection `name`. Notifications to publish afterwards are
+    /// appended to `notifications` together with their recipient.
+    fn handle(
+        &mut self,
+        name: &str,
+        request: Request,
+        keys: &Keys,
+        notifications: &mut Vec<(PublicKey, Notification)>,
+    ) -> Result<ResponseResult, NIP47Error> {
+        match request.params {
+            RequestParams::GetInfo => Ok(ResponseResult::GetInfo(GetInfoResponse {
+                alias: Some("kani".to_string()),
+                color: None,
+                pubkey: Some(keys.secret_key().public_key(SECP256K1)),
+                network: Some("regtest".to_string()),
+                block_height: None,
+                block_hash: None,
+                methods: METHODS.iter().map(Method::to_string).collect(),
+                notifications: NOTIFICATIONS.iter().map(|n| n.to_string()).collect(),
+            })),
+            RequestParams::GetBalance => Ok(ResponseResult::GetBalance(GetBalanceResponse {
+                balance: self.balance,
+            })),
+            RequestParams::MakeInvoice(params) => self.make_invoice(name, params, keys),
+            RequestParams::PayInvoice(params) => self.pay_invoice(name, params, notifications),
+            RequestParams::LookupInvoice(params) => self.lookup_invoice(name, params),
+            RequestParams::ListTransactions(params) => Ok(self.list_transactions(name, params)),
+            _ => Err(nip47_error(
+                ErrorCode::NotImplemented,
+                format!("{} is not supported", request.method),
+            )),
+        }
+    }
+
+    fn make_invoice(
+        &mut self,
+        name: &str,
+        params: MakeInvoiceRequest,
+        keys: &Keys,
+    ) -> Result<ResponseResult, NIP47Error> {
+        let (preimage, payment_hash) = random_preimage();
+        let invoice = bolt11::Invoice {
+            amount_msats: (params.amount > 0).then_some(params.amount),
+            timestamp: Timestamp::now().as_u64(),
+            expiry: params.expiry.unwrap_or(bolt11::DEFAULT_EXPIRY_SECS),
+            description: params.description,
+            description_hash: params.description_hash,
+            payment_hash: payment_hash.clone(),
+            payee: keys.secret_key().public_key(SECP256K1),
+        };
+        let encoded = bolt11::encode(INVOICE_CURRENCY, &invoice, keys.secret_key())
+            .map_err(|e| nip47_error(ErrorCode::Other, format!("Cannot make the invoice: {e}")))?;
+
+        self.transactions.push(LedgerEntry {
+            connection: name.to_string(),
+            tx: LookupInvoiceResponse {
+                transaction_type: Some(TransactionType::Incoming),
+                invoice: Some(encoded.clone()),
+                description: invoice.description.clone(),
+                description_hash: invoice.description_hash.clone(),
+                preimage: Some(preimage),
+                payment_hash: payment_hash.clone(),
+                amount: params.amount,
+                fees_paid: 0,
+                created_at: Timestamp::from(invoice.timestamp),
+                expires_at: Some(Timestamp::from(invoice.expires_at())),
+                settled_at: None,
+                metadata: None,
+            },
+        });
+        Ok(ResponseResult::MakeInvoice(MakeInvoiceResponse {
+            invoice: encoded,
+            payment_hash,
+        }))
+    }
+
+    fn pay_invoice(
+        &mut self,
+        name: &str,
+        params: PayInvoiceRequest,
+        notifications: &mut Vec<(PublicKey, Notification)>,
+    ) -> Result<ResponseResult, NIP47Error> {
+        let now = Timestamp::now();
+        let invoice = bolt11::decode(&params.invoice)
+            .map_err(|e| nip47_error(ErrorCode::Other, format!("Invalid invoice: {e}")))?;
+        let msats = invoice
+            .amount_msats
+            .or(params.amount)
+            .ok_or_else(|| nip47_error(ErrorCode::Other, "The invoice has no amount"))?;
+        if invoice.expires_at() <= now.as_u64() {
+            return Err(nip47_error(ErrorCode::Other, "The invoice has expired"));
+        }
+        if self.transactions.iter().any(|entry| {
+            entry.tx.transaction_type == Some(TransactionType::Outgoing)
+                && entry.tx.payment_hash == invoice.payment_hash
+        }) {
+            return Err(nip47_error(
+                ErrorCode::PaymentFailed,
+                "The invoice was already paid",
+            ));
+        }
+
+        let connection = self
+            .connections
+            .get_mut(name)
+            .ok_or_else(|| nip47_error(ErrorCode::Unauthorized, "Unknown connection"))?;
+        connection.renew(now.as_u64());
+        if let Some(budget) = connection.budget
+            && connection
+                .spent
+                .checked_add(msats)
+                .is_none_or(|total| total > budget)
+        {
+            return Err(nip47_error(
+                ErrorCode::QuotaExceeded,
+                format!(
+                    "Budget of {} sats exceeded ({} sats left)",
+                    budget / 1000,
+                    budget.saturating_sub(connection.spent) / 1000
+                ),
+            ));
+        }
+        if self.balance < msats {
+            return Err(nip47_error(
+                ErrorCode::InsufficientBalance,
+                format!("Balance is {} sats", self.balance / 1000),
+            ));
+        }
+        connection.spent = connection.spent.saturating_add(msats);
+        self.balance -= msats;
+
+        // An unpaid invoice of this ledger is settled internally, crediting the balance again
+        let internal = self.transactions.iter_mut().find(|entry| {
+            entry.tx.transaction_type == Some(TransactionType::Incoming)
+                && entry.tx.payment_hash == invoice.payment_hash
+                && entry.tx.settled_at.is_none()
+        });
+        let preimage = match internal {
+            Some(entry) => {
+                entry.tx.amount = msats;
+                entry.tx.settled_at = Some(now);
+                self.balance += msats;
+                if let Some(issuer) = self.connections.get(&entry.connection)
+                    && let Ok(issuer) = PublicKey::from_hex(&issuer.public_key)
+                    && let Some(notification) = entry.to_notification()
+                {
+                    notifications.push((issuer, notification));
+                }
+                entry.tx.preimage.clone().unwrap_or_default()
+            }
+            None => random_preimage().0,
+        };
+
+        let entry = LedgerEntry {
+            connection: name.to_string(),
+            tx: LookupInvoiceResponse {
+                transaction_type: Some(TransactionType::Outgoing),
+                invoice: Some(params.invoice),
+                description: invoice.description.clone(),
+                description_hash: invoice.description_hash.clone(),
+                preimage: Some(preimage.clone()),
+                payment_hash: invoice.payment_hash.clone(),
+                amount: msats,
+                fees_paid: 0,
+                created_at: now,
+                expires_at: Some(Timestamp::from(invoice.expires_at())),
+                settled_at: Some(now),
+                metadata: None,
+            },
+        };
+        if let Some(payer) = self.connections.get(name)
+            && let Ok(payer) = PublicKey::from_hex(&payer.public_key)
+            && let Some(notification) = entry.to_notification()
+        {
+            notifications.push((payer, notification));
+        }
+        self.transactions.push(entry);
+
+        Ok(ResponseResult::PayInvoice(PayInvoiceResponse {
+            preimage,
+            fees_paid: Some(0),
+        }))
+    }
+
+    fn lookup_invoice(
+        &self,
+        name: &str,
+        params: LookupInvoiceRequest,
+    ) -> Result<ResponseResult, NIP47Error> {
+        let payment_hash = match (params.payment_hash, params.invoice) {
+            (Some(payment_hash), _) => payment_hash,
+            (None, Some(invoice)) => {
+                bolt11::decode(&invoice)
+                    .map_err(|e| nip47_error(ErrorCode::Other, format!("Invalid invoice: {e}")))?
+                    .payment_hash
+            }
+            (None, None) => {
+                return Err(nip47_error(
+                    ErrorCode::Other,
+                    "payment_hash or invoice is required",
+                ));
+            }
+        };
+        self.transactions
+            .iter()
+            .rev()
+            .find(|entry| entry.connection == name && entry.tx.payment_hash == payment_hash)
+            .map(|entry| ResponseResult::LookupInvoice(entry.view()))
+            .ok_or_else(|| nip47_error(ErrorCode::NotFound, "Invoice not found"))
+    }
+
+    fn list_transactions(&self, name: &str, params: ListTransactionsRequest) -> ResponseResult {
+        let mut transactions: Vec<LookupInvoiceResponse> = self
+            .transactions
+            .iter()
+            .filter(|entry| entry.connection == name)
+            .map(LedgerEntry::view)
+            .filter(|tx| params.unpaid == Some(true) || tx.settled_at.is_some())
+            .filter(|tx| {
+                params
+                    .transaction_type
+                    .is_none_or(|kind| tx.transaction_type == Some(kind))
+            })
+            .filter(|tx| params.from.is_none_or(|from| tx.created_at >= from))
+            .filter(|tx| params.until.is_none_or(|until| tx.created_at <= until))
+            .collect();
+        transactions.sort_by_key(|tx| std::cmp::Reverse(tx.created_at));
+        let transactions = transactions
+            .into_iter()
+            .skip(params.offset.unwrap_or(0) as usize)
+            .take(params.limit.map_or(usize::MAX, |limit| limit as usize))
+            .collect();
+        ResponseResult::ListTransactions(transactions)
+    }
+}
+
+pub async fn handle_serve_command(
+    command: ServeCommand,
+    common: &CommonOptions,
+) -> Result<(), Error> {
+    let path = match command.ledger {
+        Some(path) => path,
+        None => get_config_path()?.with_file_name("ledger.json"),
+    };
+    let mut ledger = if path.exists() {
+        Ledger::load(&path)?
+    } else {
+        let ledger = Ledger::new();
+        ledger.save(&path)?;
+        eprintln!("Created ledger at {}", path.display());
+        ledger
+    };
+    let config = load_config()?;
+    let relays = get_relays(common, &config);
+
+    match command.action {
+        Some(ServeAction::Connect {
+            name,
+            budget,
+            renewal,
+        }) => {
+            if ledger.connections.contains_key(&name) {
+                return Err(Error::Message(format!(
+                    "Connection '{name}' already exists"
+                )));
+            }
+            if relays.is_empty() {
+                return Err(Error::Message(
+                    "No relays provided in args or config".to_string(),
+                ));
+            }
+            let client_secret = SecretKey::generate();
+            let now = Timestamp::now().as_u64();
+            ledger.connections.insert(
+                name.clone(),
+                Connection {
+                    public_key: Keys::new(client_secret.clone()).public_key().to_hex(),
+                    budget: budget.map(sats_to_msats).transpose()?,
+                    renewal,
+                    spent: 0,
+                    period_start: now,
+                    created_at: now,
+                },
+            );
+            ledger.save(&path)?;
+
+            let uri = NostrWalletConnectURI::new(
+                ledger.keys()?.public_key(),
+                relays
+                    .iter()
+                    .map(|url| RelayUrl::parse(url))
+                    .collect::<Result<_, _>>()?,
+                client_secret,
+                None,
+            );
+            eprintln!("Connection '{name}' created. The URI is only shown once:");
+            println!("{uri}");
+        }
+        Some(ServeAction::Connections) => {
+            println!("Wallet service: {}", ledger.keys()?.public_key().to_hex());
+            println!("Balance: {} sats", ledger.balance / 1000);
+            let now = Timestamp::now().as_u64();
+            let mut table = Table::new();
+            table.load_preset(UTF8_FULL).set_header(vec![
+                "Name",
+                "Client Public Key",
+                "Budget (sats)",
+                "Renewal",
+                "Spent (sats)",
+                "Created At",
+            ]);
+            for (name, connection) in ledger.connections.iter_mut() {
+                connection.renew(now);
+                table.add_row(vec![
+                    Cell::new(name),
+                    Cell::new(&connection.public_key),
+                    Cell::new(
+                        connection
+                            .budget
+                            .map_or("unlimited".to_string(), |b| (b / 1000).to_string()),
+                    ),
+                    Cell::new(format!("{:?}", connection.renewal).to_lowercase()),
+                    Cell::new(connection.spent / 1000),
+                    Cell::new(Timestamp::from(connection.created_at).to_human_datetime()),
+                ]);
+            }
+            println!("{table}");
+        }
+        Some(ServeAction::Deposit { amount }) => {
+            ledger.balance = sats_to_msats(amount)?
+                .checked_add(ledger.balance)
+                .ok_or_else(|| Error::Message("The balance would overflow".to_string()))?;
+            ledger.save(&path)?;
+            println!("Balance: {} sats", ledger.balance / 1000);
+        }
+        None => serve(&path, &ledger, relays).await?,
+    }
+    Ok(())
+}
+
+/// Publish the info event and answer requests until the relays go away.
+async fn serve(path: &Path, ledger: &Ledger, relays: Vec<String>) -> Result<(), Error> {
+    let keys = ledger.keys()?;
+    let client = connect_client(keys.clone(), relays).await?;
+
+    let methods: Vec<String> = METHODS.iter().map(Method::to_string).collect();
+    let notifications: Vec<String> = NOTIFICATIONS.iter().map(|n| n.to_string()).collect();
+    let info = EventBuilder::new(Kind::WalletConnectInfo, methods.join(" "))
+        .tags([
+            Tag::custom(TagKind::custom("encryption"), ["nip44_v2 nip04"]),
+            Tag::custom(TagKind::custom("notifications"), [notifications.join(" ")]),
+        ])
+        .sign_with_keys(&keys)?;
+    client.send_event(&info).await?;
+
+    let filter = Filter::new()
+        .kind(Kind::WalletConnectRequest)
+        .pubkey(keys.public_key())
+        .since(Timestamp::now());
+    let mut events = client.notifications();
+    client.subscribe(filter, None).await?;
+
+    println!("Wallet service: {}", keys.public_key().to_hex());
+    println!("Balance: {} sats", ledger.balance / 1000);
+    eprintln!("Waiting for requests. Press Ctrl+C to stop.");
+
+    loop {
+        let notification = match events.recv().await {
+            Ok(notification) => notification,
+            Err(RecvError::Lagged(skipped)) => {
+                eprintln!("Fell behind and missed {skipped} relay messages");
+                continue;
+            }
+            Err(RecvError::Closed) => {
+                return Err(Error::Message(
+                    "Stopped receiving requests from the relays".to_string(),
+                ));
+            }
+        };
+        if let RelayPoolNotification::Event { event, .. } = notification
+            && event.kind == Kind::WalletConnectRequest
+            && let Err(e) = answer(&client, &keys, path, &event).await
+        {
+            eprintln!("Could not answer request {}: {e}", event.id);
+        }
+    }
+}
+
+/// Answer one request event, in the encryption scheme it was sent with.
+async fn answer(client: &Client, keys: &Keys, path: &Path, event: &Event) -> Result<(), Error> {
+    let nip04 = is_nip04(&event.content);
+    let plaintext = decrypt_message(keys.secret_key(), &event.pubkey, &event.content)?;
+    let value: serde_json::Value = serde_json::from_str(&plaintext)?;
+    let method = value["method"].as_str().unwrap_or_default().to_string();
+
+    // Reloaded for every request so `connect` and `deposit` take effect while serving
+    let mut ledger = Ledger::load(path)?;
+    let mut notifications = Vec::new();
+    let connection = ledger.connection_of(&event.pubkey);
+    let outcome = match &connection {
+        None => Err(nip47_error(ErrorCode::Unauthorized, "Unknown connection")),
+        Some(_) if Method::from_str(&method).is_err() => Err(nip47_error(
+            ErrorCode::NotImplemented,
+            format!("{method} is not supported"),
+        )),
+        Some(name) => match Request::from_value(value) {
+            Ok(request) => ledger.handle(name, request, keys, &mut notifications),
+            Err(e) => Err(nip47_error(
+                ErrorCode::Other,
+                format!("Invalid request: {e}"),
+            )),
+        },
+    };
+    ledger.save(path)?;
+
+    let connection = connection.unwrap_or_else(|| event.pubkey.to_hex());
+    let body = match outcome {
+        Ok(result) => {
+            eprintln!("{method} from {connection}: ok");
+            serde_json::json!({ "result_type": method, "result": result })
+        }
+        Err(error) => {
+            eprintln!("{method} from {connection}: {error}");
+            serde_json::json!({ "result_type": method, "error": error })
+        }
+    };
+    let content = if nip04 {
+        nip04::encrypt(keys.secret_key(), &event.pubkey, body.to_string())?
+    } else {
+        nip44::encrypt(
+            keys.secret_key(),
+            &event.pubkey,
+            body.to_string(),
+            nip44::Version::V2,
+        )?
+    };
+    let response = EventBuilder::new(Kind::WalletConnectResponse, content)
+        .tags([Tag::public_key(event.pubkey), Tag::event(event.id)])
+        .sign_with_keys(keys)?;
+    client.send_event(&response).await?;
+
+    // Sent in both encryption schemes, clients pick the kind they understand
+    for (recipient, notification) in notifications {
+        let json = serde_json::to_string(&notification)?;
+        for (kind, content) in [
+            (
+                Kind::WalletConnectNotification,
+                nip04::encrypt(keys.secret_key(), &recipient, &json)?,
+            ),
+            (
+                WALLET_CONNECT_NOTIFICATION_NIP44,
+                nip44::encrypt(keys.secret_key(), &recipient, &json, nip44::Version::V2)?,
+            ),
+        ] {
+            let event = EventBuilder::new(kind, content)
+                .tag(Tag::public_key(recipient))
+                .sign_with_keys(keys)?;
+            client.send_event(&event).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connection(budget: Option<u64>, renewal: Renewal) -> Connection {
+        Connection {
+            public_key: Keys::generate().public_key().to_hex(),
+            budget,
+            renewal,
+            spent: 0,
+            period_start: Timestamp::now().as_u64(),
+            created_at: Timestamp::now().as_u64(),
+        }
+    }
+
+    fn invoice(ledger: &mut Ledger, name: &str, msats: u64) -> String {
+        let keys = ledger.keys().unwrap();
+        let params = MakeInvoiceRequest {
+            amount: msats,
+            description: None,
+            description_hash: None,
+            expiry: None,
+        };
+        match ledger.make_invoice(name, params, &keys).unwrap() {
+            ResponseResult::MakeInvoice(res) => res.invoice,
+            other => panic!("unexpected result {other:?}"),
+        }
+    }
+
+    fn pay(ledger: &mut Ledger, name: &str, invoice: &str) -> Result<ResponseResult, NIP47Error> {
+        let params = PayInvoiceRequest {
+            id: None,
+            invoice: invoice.to_string(),
+            amount: None,
+        };
+        ledger.pay_invoice(name, params, &mut Vec::new())
+    }
+
+    #[test]
+    fn renews_budgets_at_period_boundaries() {
+        let mut daily = connection(Some(10_000), Renewal::Daily);
+        daily.period_start = 1_000;
+        daily.spent = 5_000;
+        daily.renew(1_000 + 86_399);
+        assert_eq!(daily.spent, 5_000);
+        daily.renew(1_000 + 2 * 86_400 + 10);
+        assert_eq!(daily.spent, 0);
+        assert_eq!(daily.period_start, 1_000 + 2 * 86_400);
+
+        let mut never = connection(Some(10_000), Renewal::Never);
+        never.spent = 5_000;
+        never.renew(u64::MAX);
+        assert_eq!(never.spent, 5_000);
+    }
+
+    #[test]
+    fn settles_internal_invoices_within_the_budget_once() {
+        let mut ledger = Ledger::new();
+        ledger.balance = 100_000;
+        ledger
+            .connections
+            .insert("shop".to_string(), connection(None, Renewal::Never));
+        ledger.connections.insert(
+            "buyer".to_string(),
+            connection(Some(30_000), Renewal::Daily),
+        );
+
+        let first = invoice(&mut ledger, "shop", 20_000);
+        let mut notifications = Vec::new();
+        let params = PayInvoiceRequest {
+            id: None,
+            invoice: first.clone(),
+            amount: None,
+        };
+        ledger
+            .pay_invoice("buyer", params, &mut notifications)
+            .unwrap();
+        // Debited and credited again, with both sides notified
+        assert_eq!(ledger.balance, 100_000);
+        assert_eq!(ledger.connections["buyer"].spent, 20_000);
+        assert_eq!(notifications.len(), 2);
+        assert!(
+            ledger
+                .transactions
+                .iter()
+                .all(|entry| entry.tx.settled_at.is_some())
+        );
+
+        let again = pay(&mut ledger, "buyer", &first).unwrap_err();
+        assert_eq!(again.code, ErrorCode::PaymentFailed);
+
+        let second = invoice(&mut ledger, "shop", 20_000);
+        let over_budget = pay(&mut ledger, "buyer", &second).unwrap_err();
+        assert_eq!(over_budget.code, ErrorCode::QuotaExceeded);
+        assert_eq!(ledger.connections["buyer"].spent, 20_000);
+        assert!(pay(&mut ledger, "shop", &second).is_ok());
+    }
+
+    #[test]
+    fn rejects_amounts_that_overflow() {
+        let mut ledger = Ledger::new();
+        let keys = ledger.keys().unwrap();
+        ledger.connections.insert(
+            "buyer".to_string(),
+            connection(Some(30_000), Renewal::Never),
+        );
+        ledger.connections.get_mut("buyer").unwrap().spent = 1;
+
+        let params = MakeInvoiceRequest {
+            amount: u64::MAX,
+            description: None,
+            description_hash: None,
+            expiry: None,
+        };
+        let too_large = ledger.make_invoice("buyer", params, &keys).unwrap_err();
+        assert_eq!(too_large.code, ErrorCode::Other);
+
+        let amountless = invoice(&mut ledger, "buyer", 0);
+        let params = PayInvoiceRequest {
+            id: None,
+            invoice: amountless,
+            amount: Some(u64::MAX),
+        };
+        let over_budget = ledger
+            .pay_invoice("buyer", params, &mut Vec::new())
+            .unwrap_err();
+        assert_eq!(over_budget.code, ErrorCode::QuotaExceeded);
+        assert_eq!(ledger.connections["buyer"].spent, 1);
+    }
+}
@@ -0,0 +1,579 @@
+use crate::bolt11;
+use crate::cli::CommonOptions;
+use crate::cli::common::{connect_client, get_relays, get_signer, sats_to_msats};
+use crate::cli::nip47::{nwc_request, resolve_wallet};
+use crate::cli::wallet::{reserve_payment, settle_payment};
+use crate::config::load_config;
+use crate::error::Error;
+use crate::rpc;
+use bech32::{Bech32, Hrp};
+use clap::Parser;
+use colored::*;
+use comfy_table::{Cell, Table, presets::UTF8_FULL};
+use nostr::hashes::{Hash, sha256};
+use nostr::nips::nip47::{PayInvoiceRequest, Request};
+use nostr::nips::nip57::ZapRequestData;
+use nostr::prelude::{FromBech32, ToBech32};
+use nostr_sdk::prelude::*;
+use serde::Deserialize;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::time::Duration;
+
+#[derive(Parser, Clone)]
+#[command(subcommand_negates_reqs = true)]
+pub struct ZapCommand {
+    #[command(subcommand)]
+    subcommand: Option<ZapSubcommand>,
+    /// Profile or event to zap (npub, nprofile, note, nevent or hex public key)
+    #[clap(required = true)]
+    target: Option<String>,
+    /// Amount in sats
+    #[clap(long, required = true)]
+    amount: Option<u64>,
+    /// Message sent along with the zap
+    #[clap(long)]
+    comment: Option<String>,
+    #[command(flatten)]
+    common: CommonOptions,
+    /// Stored wallet to pay with instead of the default one
+    #[clap(long)]
+    wallet: Option<String>,
+    /// Seconds to wait for the wallet and for the zap receipt
+    #[clap(long, default_value_t = rpc::DEFAULT_TIMEOUT_SECS)]
+    timeout: u64,
+}
+
+#[derive(Parser, Clone)]
+pub enum ZapSubcommand {
+    /// List and validate the zap receipts of a profile or event
+    List {
+        /// Profile or event (npub, nprofile, note, nevent or hex public key)
+        target: String,
+        /// Maximum number of receipts to fetch
+        #[clap(long, default_value_t = 500)]
+        limit: usize,
+    },
+}
+
+/// The LUD-06 pay request of a LNURL-pay endpoint, with the NIP-57 extensions.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PayRequest {
+    pub callback: String,
+    /// Smallest amount in millisatoshis
+    pub min_sendable: u64,
+    /// Largest amount in millisatoshis
+    pub max_sendable: u64,
+    #[serde(default)]
+    pub allows_nostr: bool,
+    /// Hex public key the endpoint signs zap receipts with
+    pub nostr_pubkey: Option<String>,
+}
+
+/// LNURL endpoints answer either with their payload or with an error status.
+#[derive(Deserialize)]
+struct LnurlStatus {
+    status: Option<String>,
+    reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CallbackResponse {
+    pr: String,
+}
+
+/// A pubkey or an event to zap.
+pub enum ZapTarget {
+    Profile(PublicKey),
+    Event(EventId),
+}
+
+pub async fn handle_zap_command(command: ZapCommand) -> Result<(), Error> {
+    match &command.subcommand {
+        Some(ZapSubcommand::List { target, limit }) => {
+            list_zaps(target, *limit, &command.common).await
+        }
+        None => send_zap(command).await,
+    }
+}
+
+async fn send_zap(command: ZapCommand) -> Result<(), Error> {
+    let config = load_config()?;
+    let relays = get_relays(&command.common, &config);
+    let signer = get_signer(&command.common, &config)?;
+    let client = connect_client(signer, relays.clone()).await?;
+    let wait = Duration::from_secs(command.timeout);
+    // Both are required by clap unless a subcommand is given
+    let amount = command.amount.unwrap_or_default();
+    let msats = sats_to_msats(amount)?;
+
+    let target = parse_target(command.target.as_deref().unwrap_or_default())?;
+    let (recipient, event_id) = match target {
+        ZapTarget::Profile(public_key) => (public_key, None),
+        ZapTarget::Event(event_id) => {
+            let event = fetch_event(&client, event_id).await?;
+            (event.pubkey, Some(event_id))
+        }
+    };
+
+    let metadata = fetch_metadata(&client, recipient).await?;
+    let url = lnurl_pay_url(&metadata)?;
+    let pay_request = fetch_pay_request(&url).await?;
+    let zapper = zapper_public_key(&pay_request)?;
+    if msats < pay_request.min_sendable || msats > pay_request.max_sendable {
+        return Err(Error::Message(format!(
+            "The recipient accepts between {} and {} sats",
+            pay_request.min_sendable.div_ceil(1000),
+            pay_request.max_sendable / 1000
+        )));
+    }
+
+    let lnurl = bech32::encode::<Bech32>(Hrp::parse("lnurl").unwrap(), url.as_str().as_bytes())
+        .map_err(|e| Error::Message(format!("Could not encode LNURL: {e}")))?;
+    let mut data = ZapRequestData::new(
+        recipient,
+        relays
+            .iter()
+            .map(|url| RelayUrl::parse(url))
+            .collect::<Result<Vec<_>, _>>()?,
+    )
+    .amount(msats)
+    .lnurl(&lnurl)
+    .message(command.comment.unwrap_or_default());
+    if let Some(event_id) = event_id {
+        data = data.event_id(event_id);
+    }
+    let zap_request = client
+        .sign_event_builder(EventBuilder::public_zap_request(data))
+        .await?;
+
+    let invoice = fetch_zap_invoice(&pay_request, &zap_request, msats, &lnurl).await?;
+    let decoded = bolt11::decode(&invoice)?;
+    if decoded.amount_msats != Some(msats) {
+        return Err(Error::Message(format!(
+            "The invoice is for {} msats instead of {msats} msats, not paying it",
+            decoded
+                .amount_msats
+                .map_or("an open amount of".to_string(), |a| a.to_string())
+        )));
+    }
+
+    // Subscribe before paying so a fast receipt is not missed; receipts are timestamped by the
+    // recipient's server, whose clock may be a little behind ours
+    let filter = Filter::new()
+        .kind(Kind::ZapReceipt)
+        .pubkey(recipient)
+        .since(Timestamp::from(
+            zap_request.created_at.as_u64().saturating_sub(60),
+        ));
+    let mut notifications = client.notifications();
+    let subscription_id = client.subscribe(filter, None).await?.val;
+
+    let (nwc_uri, wallet_name) = resolve_wallet(None, command.wallet.as_ref(), &command.common)?;
+    if let Some(name) = &wallet_name {
+        reserve_payment(name, &config, &[msats])?;
+    }
+    let params = PayInvoiceRequest {
+        id: None,
+        invoice,
+        amount: None,
+    };
+    let paid = nwc_request(&nwc_uri, Request::pay_invoice(params), wait)
+        .await
+        .and_then(|response| Ok(response.to_pay_invoice()?));
+    if let Some(name) = &wallet_name {
+        settle_payment(
+            name,
+            msats,
+            paid.as_ref().map(|res| res.fees_paid.unwrap_or(0)),
+        )?;
+    }
+    paid?;
+    println!("Zapped {amount} sats to {}", recipient.to_bech32().unwrap());
+
+    eprintln!("Waiting for the zap receipt...");
+    let receipt = tokio::time::timeout(wait, async {
+        while let Ok(notification) = notifications.recv().await {
+            if let RelayPoolNotification::Event {
+                subscription_id: id,
+                event,
+                ..
+            } = notification
+                && id == subscription_id
+                && embedded_zap_request(&event).is_some_and(|request| request.id == zap_request.id)
+            {
+                return Some(event);
+            }
+        }
+        None
+    })
+    .await
+    .ok()
+    .flatten();
+    client.unsubscribe(&subscription_id).await;
+
+    match receipt {
+        Some(receipt) => {
+            println!("Zap receipt: {}", receipt.id.to_bech32().unwrap());
+            println!("From: {}", receipt.pubkey.to_bech32().unwrap());
+            if let Some(amount) = receipt
+                .tags
+                .find(TagKind::Bolt11)
+                .and_then(|tag| tag.content())
+                .and_then(|invoice| bolt11::decode(invoice).ok())
+                .and_then(|invoice| invoice.amount_msats)
+            {
+                println!("Amount: {} sats", amount.div_ceil(1000));
+            }
+            println!("Published at: {}", receipt.created_at.to_human_datetime());
+            if receipt.pubkey != zapper {
+                println!(
+                    "{}",
+                    "Warning: the receipt is not signed by the recipient's LNURL server.".yellow()
+                );
+            }
+        }
+        None => eprintln!(
+            "{}",
+            format!(
+                "No zap receipt within {} seconds. The payment itself went through.",
+                command.timeout
+            )
+            .yellow()
+        ),
+    }
+
+    client.shutdown().await;
+    Ok(())
+}
+
+async fn list_zaps(target: &str, limit: usize, common: &CommonOptions) -> Result<(), Error> {
+    let config = load_config()?;
+    let relays = get_relays(common, &config);
+    let client = connect_client(Keys::generate(), relays).await?;
+
+    let (recipient, filter) = match parse_target(target)? {
+        ZapTarget::Profile(public_key) => (public_key, Filter::new().pubkey(public_key)),
+        ZapTarget::Event(event_id) => {
+            let event = fetch_event(&client, event_id).await?;
+            (event.pubkey, Filter::new().event(event_id))
+        }
+    };
+
+    // Without the LNURL server's key the receipts can still be checked, just not their author
+    let zapper = match fetch_zapper(&client, recipient).await {
+        Ok(zapper) => Some(zapper),
+        Err(e) => {
+            let warning = format!(
+                "Could not look up the recipient's zap server ({e}), receipt authors are not checked."
+            );
+            eprintln!("{}", warning.yellow());
+            None
+        }
+    };
+
+    let receipts = client
+        .fetch_events(
+            filter.kind(Kind::ZapReceipt).limit(limit),
+            Duration::from_secs(10),
+        )
+        .await?;
+    client.shutdown().await;
+
+    let mut totals: HashMap<PublicKey, (usize, u64)> = HashMap::new();
+    let mut invalid = Table::new();
+    invalid
+        .load_preset(UTF8_FULL)
+        .set_header(vec!["Receipt", "Author", "Amount (sats)", "Reason"]);
+    let mut invalid_count = 0;
+    for receipt in receipts.iter() {
+        match validate_receipt(receipt, recipient, zapper) {
+            Ok((request, msats)) => {
+                let total = totals.entry(request.pubkey).or_default();
+                total.0 += 1;
+                total.1 += msats;
+            }
+            Err(reason) => {
+                invalid_count += 1;
+                let amount = receipt
+                    .tags
+                    .find(TagKind::Bolt11)
+                    .and_then(|tag| tag.content())
+                    .and_then(|invoice| bolt11::decode(invoice).ok())
+                    .and_then(|invoice| invoice.amount_msats)
+                    .map(|msats| (msats / 1000).to_string())
+                    .unwrap_or_default();
+                invalid.add_row(vec![
+                    Cell::new(receipt.id.to_bech32().unwrap()),
+                    Cell::new(receipt.pubkey.to_bech32().unwrap()),
+                    Cell::new(amount),
+                    Cell::new(reason),
+                ]);
+            }
+        }
+    }
+
+    let mut senders: Vec<_> = totals.into_iter().collect();
+    senders.sort_by_key(|(_, (_, msats))| Reverse(*msats));
+    let mut table = Table::new();
+    table
+        .load_preset(UTF8_FULL)
+        .set_header(vec!["Sender", "Zaps", "Total (sats)"]);
+    let (mut zaps, mut msats) = (0, 0);
+    for (sender, (count, total)) in &senders {
+        zaps += count;
+        msats += total;
+        table.add_row(vec![
+            Cell::new(sender.to_bech32().unwrap()),
+            Cell::new(count),
+            Cell::new(total / 1000),
+        ]);
+    }
+    println!("{table}");
+    println!(
+        "{zaps} zaps from {} senders, {} sats",
+        senders.len(),
+        msats / 1000
+    );
+
+    if invalid_count > 0 {
+        println!(
+            "{}",
+            format!("{invalid_count} fake or invalid receipts, not counted above:")
+                .red()
+                .bold()
+        );
+        println!("{invalid}");
+    }
+    Ok(())
+}
+
+/// Check a kind-9735 receipt per NIP-57 and return its zap request and amount in msats, or why
+/// it cannot be trusted. `zapper` is the recipient's LNURL `nostrPubkey`, when known.
+pub fn validate_receipt(
+    receipt: &Event,
+    recipient: PublicKey,
+    zapper: Option<PublicKey>,
+) -> Result<(Event, u64), String> {
+    if let Some(zapper) = zapper
+        && receipt.pubkey != zapper
+    {
+        return Err("not signed by the recipient's zap server".to_string());
+    }
+    let description = receipt
+        .tags
+        .find(TagKind::Description)
+        .and_then(|tag| tag.content())
+        .ok_or("no zap request")?;
+    let request = Event::from_json(description).map_err(|_| "malformed zap request")?;
+    if request.kind != Kind::ZapRequest {
+        return Err(format!("zap request has kind {}", request.kind));
+    }
+    if request.verify().is_err() {
+        return Err("invalid zap request signature".to_string());
+    }
+    if request.tags.public_keys().next() != Some(&recipient) {
+        return Err("zap request is for someone else".to_string());
+    }
+    let invoice = receipt
+        .tags
+        .find(TagKind::Bolt11)
+        .and_then(|tag| tag.content())
+        .and_then(|invoice| bolt11::decode(invoice).ok())
+        .ok_or("missing or invalid bolt11 invoice")?;
+    let msats = invoice.amount_msats.ok_or("invoice has no amount")?;
+    if let Some(hash) = &invoice.description_hash
+        && *hash != sha256::Hash::hash(description.as_bytes()).to_string()
+    {
+        return Err("invoice is not for this zap request".to_string());
+    }
+    if let Some(requested) = request
+        .tags
+        .find(TagKind::Amount)
+        .and_then(|tag| tag.content())
+        && requested.parse::<u64>().ok() != Some(msats)
+    {
+        return Err(format!(
+            "invoice is for {msats} msats but {requested} msats were requested"
+        ));
+    }
+    Ok((request, msats))
+}
+
+/// The key the recipient's LNURL server signs zap receipts with.
+async fn fetch_zapper(client: &Client, recipient: PublicKey) -> Result<PublicKey, Error> {
+    let metadata = fetch_metadata(client, recipient).await?;
+    let pay_request = fetch_pay_request(&lnurl_pay_url(&metadata)?).await?;
+    zapper_public_key(&pay_request)
+}
+
+/// Parse an npub, nprofile, note, nevent (optionally as a `nostr:` URI) or hex public key.
+pub fn parse_target(input: &str) -> Result<ZapTarget, Error> {
+    let input = input.trim().trim_start_matches("nostr:");
+    if let Ok(public_key) = PublicKey::from_hex(input) {
+        return Ok(ZapTarget::Profile(public_key));
+    }
+    match Nip19::from_bech32(input)? {
+        Nip19::Pubkey(public_key) => Ok(ZapTarget::Profile(public_key)),
+        Nip19::Profile(profile) => Ok(ZapTarget::Profile(profile.public_key)),
+        Nip19::EventId(event_id) => Ok(ZapTarget::Event(event_id)),
+        Nip19::Event(event) => Ok(ZapTarget::Event(event.event_id)),
+        _ => Err(Error::Message(
+            "Expected an npub, nprofile, note or nevent".to_string(),
+        )),
+    }
+}
+
+pub async fn fetch_event(client: &Client, event_id: EventId) -> Result<Event, Error> {
+    let events = client
+        .fetch_events(Filter::new().id(event_id), Duration::from_secs(10))
+        .await?;
+    events
+        .first_owned()
+        .ok_or_else(|| Error::Message("Event not found.".to_string()))
+}
+
+pub async fn fetch_metadata(client: &Client, public_key: PublicKey) -> Result<Metadata, Error> {
+    let filter = Filter::new()
+        .author(public_key)
+        .kind(Kind::Metadata)
+        .limit(1);
+    let events = client.fetch_events(filter, Duration::from_secs(10)).await?;
+    let event = events
+        .first_owned()
+        .ok_or_else(|| Error::Message("The recipient has no profile (kind 0).".to_string()))?;
+    Ok(Metadata::from_json(&event.content)?)
+}
+
+/// LNURL-pay endpoint from the profile's `lud16` lightning address or `lud06` LNURL.
+pub fn lnurl_pay_url(metadata: &Metadata) -> Result<Url, Error> {
+    if let Some(address) = &metadata.lud16 {
+        let (user, domain) = address
+            .split_once('@')
+            .ok_or_else(|| Error::Message(format!("Invalid lightning address: {address}")))?;
+        // LUD-16 uses plain http only for onion services
+        let scheme = if domain.ends_with(".onion") {
+            "http"
+        } else {
+            "https"
+        };
+        return Ok(Url::parse(&format!(
+            "{scheme}://{domain}/.well-known/lnurlp/{user}"
+        ))?);
+    }
+    if let Some(lnurl) = &metadata.lud06 {
+        let lnurl = lnurl.trim_start_matches("lightning:");
+        let (hrp, data) = bech32::decode(lnurl)?;
+        if hrp.to_lowercase() != "lnurl" {
+            return Err(Error::Message(format!("Invalid LNURL: {lnurl}")));
+        }
+        let url = String::from_utf8(data)
+            .map_err(|_| Error::Message(format!("Invalid LNURL: {lnurl}")))?;
+        return Ok(Url::parse(&url)?);
+    }
+    Err(Error::Message(
+        "The recipient has no lightning address (lud16) or LNURL (lud06) in their profile."
+            .to_string(),
+    ))
+}
+
+/// GET an LNURL endpoint, turning `{"status": "ERROR"}` answers into errors.
+async fn lnurl_get<T: for<'de> Deserialize<'de>>(url: &Url) -> Result<T, Error> {
+    let body = reqwest::get(url.as_str()).await?.text().await?;
+    if let Ok(LnurlStatus {
+        status: Some(status),
+        reason,
+    }) = serde_json::from_str(&body)
+        && status.eq_ignore_ascii_case("ERROR")
+    {
+        return Err(Error::Message(format!(
+            "LNURL error: {}",
+            reason.unwrap_or_default()
+        )));
+    }
+    Ok(serde_json::from_str(&body)?)
+}
+
+pub async fn fetch_pay_request(url: &Url) -> Result<PayRequest, Error> {
+    lnurl_get(url).await
+}
+
+/// Public key that signs the endpoint's zap receipts; fails when it does not support zaps.
+pub fn zapper_public_key(pay_request: &PayRequest) -> Result<PublicKey, Error> {
+    match &pay_request.nostr_pubkey {
+        Some(public_key) if pay_request.allows_nostr => Ok(PublicKey::from_hex(public_key)?),
+        _ => Err(Error::Message(
+            "The recipient's lightning address does not support zaps.".to_string(),
+        )),
+    }
+}
+
+async fn fetch_zap_invoice(
+    pay_request: &PayRequest,
+    zap_request: &Event,
+    msats: u64,
+    lnurl: &str,
+) -> Result<String, Error> {
+    let mut callback = Url::parse(&pay_request.callback)?;
+    callback
+        .query_pairs_mut()
+        .append_pair("amount", &msats.to_string())
+        .append_pair("nostr", &zap_request.as_json())
+        .append_pair("lnurl", lnurl);
+    let response: CallbackResponse = lnurl_get(&callback).await?;
+    Ok(response.pr)
+}
+
+/// The kind-9734 zap request a receipt carries in its `description` tag.
+pub fn embedded_zap_request(receipt: &Event) -> Option<Event> {
+    let description = receipt.tags.find(TagKind::Description)?.content()?;
+    Event::from_json(description).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr::secp256k1::{Secp256k1, SecretKey as NodeKey};
+
+    fn receipt(amount_msats: u64, requested_msats: u64, signer: &Keys) -> (Event, PublicKey) {
+        let sender = Keys::generate();
+        let recipient = Keys::generate().public_key();
+        let relays = vec![RelayUrl::parse("wss://relay.example.com").unwrap()];
+        let data = ZapRequestData::new(recipient, relays).amount(requested_msats);
+        let request = EventBuilder::public_zap_request(data)
+            .sign_with_keys(&sender)
+            .unwrap();
+        let node_key = NodeKey::from_slice(&[7; 32]).unwrap();
+        let invoice = bolt11::Invoice {
+            amount_msats: Some(amount_msats),
+            timestamp: 1_700_000_000,
+            expiry: 600,
+            description: None,
+            description_hash: Some(sha256::Hash::hash(request.as_json().as_bytes()).to_string()),
+            payment_hash: "ab".repeat(32),
+            payee: node_key.public_key(&Secp256k1::new()),
+        };
+        let invoice = bolt11::encode("bc", &invoice, &node_key).unwrap();
+        let receipt = EventBuilder::zap_receipt(invoice, None::<String>, &request)
+            .sign_with_keys(signer)
+            .unwrap();
+        (receipt, recipient)
+    }
+
+    #[test]
+    fn validates_zap_receipts() {
+        let zapper = Keys::generate();
+
+        let (valid, recipient) = receipt(21_000, 21_000, &zapper);
+        let (_, msats) = validate_receipt(&valid, recipient, Some(zapper.public_key())).unwrap();
+        assert_eq!(msats, 21_000);
+
+        let (forged, recipient) = receipt(21_000, 21_000, &Keys::generate());
+        assert!(validate_receipt(&forged, recipient, Some(zapper.public_key())).is_err());
+        assert!(validate_receipt(&forged, recipient, None).is_ok());
+
+        let (short, recipient) = receipt(1_000, 21_000, &zapper);
+        assert!(validate_receipt(&short, recipient, Some(zapper.public_key())).is_err());
+    }
+}
@@ -1,16 +1,92 @@
+use colored::*;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs;
-use std::path::PathBuf;
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
 
 #[derive(Deserialize, Serialize, Default, Debug)]
 pub struct Config {
     pub secret_key: Option<String>,
     pub relays: Option<Vec<String>>,
     pub encrypted_secret_key: Option<String>,
+    /// NIP-46 bunker URI used as the signer instead of a local key
+    pub bunker_uri: Option<String>,
+    /// Local app key (bech32) used to talk to the bunker
+    pub bunker_app_key: Option<String>,
+    /// Identity used when `--identity` is not given
+    pub default_identity: Option<String>,
+    /// Wallet used by `nip47` when neither a URI nor `--wallet` is given
+    pub default_wallet: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub identities: BTreeMap<String, Identity>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub wallets: BTreeMap<String, Wallet>,
+}
+
+/// A named account with its own key, relays and wallet.
+#[derive(Deserialize, Serialize, Default, Debug, Clone)]
+pub struct Identity {
+    /// Public key (bech32), kept for display without decrypting the secret key
+    pub public_key: Option<String>,
+    /// NIP-49 encrypted secret key (bech32)
+    pub encrypted_secret_key: Option<String>,
+    pub relays: Option<Vec<String>>,
+    /// Stored wallet (see `nip47 wallet`) used by default
+    pub wallet: Option<String>,
+    /// Plaintext Wallet Connect URI written by older versions, moved into a stored wallet by
+    /// `nip47 wallet add --from-identity`
+    pub nwc_uri: Option<String>,
+    /// NIP-46 bunker URI used as the signer instead of the local key
+    pub bunker_uri: Option<String>,
+    /// Local app key (bech32) used to talk to the bunker
+    pub bunker_app_key: Option<String>,
+}
+
+/// A stored Nostr Wallet Connect connection.
+#[derive(Deserialize, Serialize, Default, Debug, Clone)]
+pub struct Wallet {
+    /// Public key (hex) of the wallet service
+    pub public_key: String,
+    pub relays: Vec<String>,
+    pub lud16: Option<String>,
+    /// NIP-49 encrypted connection secret (bech32)
+    pub encrypted_secret: String,
+    /// Largest single payment in sats
+    pub max_payment: Option<u64>,
+    /// Sats the wallet may spend per day (UTC)
+    pub daily_limit: Option<u64>,
+}
+
+/// What each stored wallet has paid today, kept next to the config for the daily limits.
+#[derive(Deserialize, Serialize, Default, Debug)]
+pub struct Spending {
+    #[serde(default)]
+    pub wallets: BTreeMap<String, DailySpending>,
+}
+
+#[derive(Deserialize, Serialize, Default, Debug, Clone, Copy)]
+pub struct DailySpending {
+    /// Days since the unix epoch (UTC)
+    pub day: u64,
+    pub msats: u64,
 }
 
 use crate::error::Error;
 
+/// Keys holding secrets, plaintext or encrypted, at any level of the config. `config show`
+/// redacts them and [`load_config`] warns when a file containing them is readable by others.
+pub const SECRET_KEYS: &[&str] = &[
+    "secret_key",
+    "encrypted_secret_key",
+    "encrypted_secret",
+    "nwc_uri",
+    "bunker_uri",
+    "bunker_app_key",
+];
+
 pub fn get_config_path() -> Result<PathBuf, Error> {
     let path = dirs::config_dir()
         .ok_or(Error::Message(
@@ -18,6 +94,8 @@ pub fn get_config_path() -> Result<PathBuf, Error> {
         ))?
         .join("kani");
     fs::create_dir_all(&path)?;
+    #[cfg(unix)]
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o700))?;
     Ok(path.join("config.toml"))
 }
 
@@ -28,15 +106,122 @@ pub fn load_config() -> Result<Config, Error> {
         return Ok(Config::default());
     }
 
-    let content = fs::read_to_string(config_path)?;
+    let content = fs::read_to_string(&config_path)?;
     let config: Config = toml::from_str(&content)?;
 
+    let secrets = secret_paths(&toml::Value::try_from(&config)?, "");
+    if !secrets.is_empty() && is_readable_by_others(&config_path)? {
+        eprintln!(
+            "{}",
+            format!(
+                "WARNING: {} contains secrets ({}) and is readable by other users.\n\
+                 Run `chmod 600 {}`.",
+                config_path.display(),
+                secrets.join(", "),
+                config_path.display()
+            )
+            .red()
+            .bold()
+        );
+    }
+
     Ok(config)
 }
 
+/// Dotted paths of the [`SECRET_KEYS`] set anywhere in `value`.
+fn secret_paths(value: &toml::Value, prefix: &str) -> Vec<String> {
+    let Some(table) = value.as_table() else {
+        return Vec::new();
+    };
+    table
+        .iter()
+        .flat_map(|(key, value)| {
+            let path = format!("{prefix}{key}");
+            if SECRET_KEYS.contains(&key.as_str()) {
+                vec![path]
+            } else {
+                secret_paths(value, &format!("{path}."))
+            }
+        })
+        .collect()
+}
+
+#[cfg(unix)]
+fn is_readable_by_others(path: &Path) -> Result<bool, Error> {
+    Ok(fs::metadata(path)?.permissions().mode() & 0o077 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_readable_by_others(_path: &Path) -> Result<bool, Error> {
+    Ok(false)
+}
+
+fn get_spending_path() -> Result<PathBuf, Error> {
+    Ok(get_config_path()?.with_file_name("spending.toml"))
+}
+
+pub fn load_spending() -> Result<Spending, Error> {
+    let path = get_spending_path()?;
+    if !path.exists() {
+        return Ok(Spending::default());
+    }
+    Ok(toml::from_str(&fs::read_to_string(&path)?)?)
+}
+
+pub fn save_spending(spending: &Spending) -> Result<(), Error> {
+    write_atomically(&get_spending_path()?, &toml::to_string(spending)?)
+}
+
 pub fn save_config(config: &Config) -> Result<(), Error> {
-    let config_path = get_config_path()?;
     let content = toml::to_string(config)?;
-    fs::write(config_path, content)?;
+    save_config_raw(&content)
+}
+
+/// Write already validated TOML to the config file, keeping comments and layout as is.
+///
+/// The content goes to a temporary file in the same directory which is then renamed over
+/// the config, so a crash never leaves a truncated file behind.
+pub fn save_config_raw(content: &str) -> Result<(), Error> {
+    write_atomically(&get_config_path()?, content)
+}
+
+/// Replace `path` with `content` atomically, readable only by the owner.
+pub fn write_atomically(path: &Path, content: &str) -> Result<(), Error> {
+    let dir = path
+        .parent()
+        .ok_or(Error::Message("Invalid config path".to_string()))?;
+
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    #[cfg(unix)]
+    file.as_file()
+        .set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all(content.as_bytes())?;
+    file.as_file().sync_all()?;
+    file.persist(path).map_err(|e| e.error)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_secrets_in_identities() {
+        let config: Config = toml::from_str(
+            r#"
+            relays = ["wss://relay.example.com"]
+
+            [identities.bot]
+            public_key = "npub1..."
+            nwc_uri = "nostr+walletconnect://...?secret=..."
+            bunker_app_key = "nsec1..."
+            "#,
+        )
+        .unwrap();
+        let secrets = secret_paths(&toml::Value::try_from(&config).unwrap(), "");
+        assert_eq!(
+            secrets,
+            ["identities.bot.bunker_app_key", "identities.bot.nwc_uri"]
+        );
+    }
+}
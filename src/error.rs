@@ -50,12 +50,18 @@ pub enum Error {
     #[error("NIP-21 error: {0}")]
     Nip21(#[from] nostr_sdk::nips::nip21::Error),
 
+    #[error("NIP-04 error: {0}")]
+    Nip04(#[from] nostr::nips::nip04::Error),
+
     #[error("NIP-05 error: {0}")]
     Nip05(#[from] nostr::nips::nip05::Error),
 
     #[error("NIP-46 error: {0}")]
     Nip46(#[from] nostr::nips::nip46::Error),
 
+    #[error("Nostr Connect error: {0}")]
+    NostrConnect(#[from] nostr_connect::error::Error),
+
     #[error("NIP-47 error: {0}")]
     Nip47(#[from] nostr::nips::nip47::Error),
 
@@ -68,6 +74,15 @@ pub enum Error {
     #[error("NIP-59 error: {0}")]
     Nip59(#[from] nostr::nips::nip59::Error),
 
+    #[error("Signer error: {0}")]
+    Signer(#[from] nostr::signer::SignerError),
+
+    #[error("Invalid invoice: {0}")]
+    Bolt11(#[from] crate::bolt11::Bolt11Error),
+
+    #[error("{0}")]
+    Rpc(#[from] crate::rpc::RpcError),
+
     #[error("Tag error: {0}")]
     Tag(#[from] nostr_sdk::event::tag::Error),
 
@@ -80,3 +95,19 @@ pub enum Error {
     #[error("Dialoguer error: {0}")]
     Dialoguer(#[from] dialoguer::Error),
 }
+
+impl Error {
+    /// Process exit code, so scripts can tell failures apart.
+    pub fn exit_code(&self) -> u8 {
+        use crate::rpc::RpcError;
+
+        match self {
+            Error::Rpc(RpcError::Timeout(_)) => 3,
+            Error::Rpc(RpcError::Decrypt(_)) => 4,
+            Error::Rpc(RpcError::Remote(_)) => 5,
+            Error::Rpc(RpcError::Send(_)) => 6,
+            Error::Rpc(RpcError::Disconnected) => 7,
+            _ => 1,
+        }
+    }
+}
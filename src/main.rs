@@ -1,13 +1,22 @@
+#[cfg(unix)]
+mod agent;
+mod bolt11;
 mod cli;
 mod config;
 mod error;
+mod rpc;
 
-use anyhow::Result;
+use std::process::ExitCode;
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    cli::run().await?;
-    Ok(())
+async fn main() -> ExitCode {
+    match cli::run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            ExitCode::from(e.exit_code())
+        }
+    }
 }
 
 #[cfg(test)]
@@ -17,7 +26,7 @@ mod tests {
 
     #[test]
     fn test_parse_generate_command() {
-        let _cli = Cli::try_parse_from(&["nostr-tool", "key", "generate"]).unwrap();
+        let _cli = Cli::try_parse_from(["nostr-tool", "key", "generate"]).unwrap();
         // We can't easily test the subcommand matching in the new structure without making fields public.
         // For now, just parsing is a good enough smoke test.
     }
@@ -0,0 +1,140 @@
+//! Request/response over Nostr events, shared by NIP-46 and NIP-47.
+//!
+//! A request is a signed event addressed to a responder. Responses are recognised either by an
+//! `e` tag pointing at the request (NIP-47) or by an id inside the decrypted payload (NIP-46),
+//! which the caller checks in its `handle` callback.
+
+use crate::error::Error;
+use nostr_sdk::prelude::*;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{Duration, sleep, timeout};
+
+/// Seconds to wait for a response unless `--timeout` says otherwise.
+pub const DEFAULT_TIMEOUT_SECS: u64 = 30;
+
+/// How many times to publish a request before giving up when no relay accepts it.
+const SEND_ATTEMPTS: u32 = 3;
+
+#[derive(thiserror::Error, Debug)]
+pub enum RpcError {
+    #[error("No relay accepted the request: {0}")]
+    Send(String),
+
+    #[error("No response within {0} seconds")]
+    Timeout(u64),
+
+    #[error("Could not decrypt the response: {0}")]
+    Decrypt(String),
+
+    #[error("Remote error: {0}")]
+    Remote(String),
+
+    /// The request went out, but the relays went away before a response arrived.
+    #[error("All relay connections closed before a response arrived")]
+    Disconnected,
+}
+
+/// How responses to a request are recognised.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Match {
+    /// Responses carry an `e` tag with the request event id.
+    EventTag,
+    /// Responses carry an id inside their payload, checked by the caller.
+    Payload,
+}
+
+/// Publish `request` and return the first response `handle` accepts.
+///
+/// The subscription is opened before the request is published so fast responders are not
+/// missed. `handle` returns `Ok(None)` to keep waiting; a decrypt failure is remembered and
+/// reported instead of a plain timeout if nothing usable arrives.
+pub async fn call<T, F>(
+    client: &Client,
+    request: &Event,
+    response_kind: Kind,
+    responder: PublicKey,
+    matching: Match,
+    wait: Duration,
+    mut handle: F,
+) -> Result<T, Error>
+where
+    F: FnMut(&Event) -> Result<Option<T>, Error>,
+{
+    let mut filter = Filter::new()
+        .kind(response_kind)
+        .author(responder)
+        .pubkey(request.pubkey)
+        .since(request.created_at);
+    if matching == Match::EventTag {
+        filter = filter.event(request.id);
+    }
+
+    let mut notifications = client.notifications();
+    let subscription_id = client.subscribe(filter, None).await?.val;
+
+    let mut decrypt_error = None;
+    let fut = async {
+        publish(client, request).await?;
+        loop {
+            let notification = match notifications.recv().await {
+                Ok(notification) => notification,
+                // Missed messages may include the response; keep waiting for a resend or time out
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return Err(RpcError::Disconnected.into()),
+            };
+            if let RelayPoolNotification::Event {
+                subscription_id: id,
+                event,
+                ..
+            } = notification
+                && id == subscription_id
+            {
+                match handle(&event) {
+                    Ok(Some(response)) => return Ok(response),
+                    Ok(None) => {}
+                    Err(Error::Rpc(RpcError::Decrypt(e))) => decrypt_error = Some(e),
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+    };
+    let result = timeout(wait, fut).await;
+    client.unsubscribe(&subscription_id).await;
+
+    match result {
+        Ok(result) => result,
+        Err(_) => Err(match decrypt_error {
+            Some(e) => RpcError::Decrypt(e),
+            None => RpcError::Timeout(wait.as_secs()),
+        }
+        .into()),
+    }
+}
+
+/// Send `event` to every relay of `client`, retrying while none of them has accepted it.
+async fn publish(client: &Client, event: &Event) -> Result<(), Error> {
+    let mut failure = String::new();
+    for attempt in 0..SEND_ATTEMPTS {
+        if attempt > 0 {
+            sleep(Duration::from_secs(1 << (attempt - 1))).await;
+        }
+        match client.send_event(event).await {
+            Ok(output) if !output.success.is_empty() => {
+                for (url, e) in output.failed.iter() {
+                    eprintln!("Relay {url} rejected the request: {e}");
+                }
+                return Ok(());
+            }
+            Ok(output) => {
+                failure = output
+                    .failed
+                    .iter()
+                    .map(|(url, e)| format!("{url}: {e}"))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+            }
+            Err(e) => failure = e.to_string(),
+        }
+    }
+    Err(RpcError::Send(failure).into())
+}